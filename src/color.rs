@@ -0,0 +1,64 @@
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Whether output meant for people (errors, the REPL prompt, disassembly) is
+// colored. Off until set, so embedders get plain text.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub const RED: &str = "\x1b[31m";
+pub const GREEN: &str = "\x1b[32m";
+pub const YELLOW: &str = "\x1b[33m";
+pub const CYAN: &str = "\x1b[36m";
+pub const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    Auto, // Only when stdout is a terminal and NO_COLOR isn't set
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(s: &str) -> Option<ColorChoice> {
+        match s {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+
+    // Whether this choice colors output going to a terminal or not. A
+    // NO_COLOR variable that isn't empty turns Auto off, see no-color.org.
+    pub fn resolve(self, terminal: bool) -> bool {
+        match self {
+            ColorChoice::Auto => terminal && env::var_os("NO_COLOR").is_none_or(|x| x.is_empty()),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+pub fn set(choice: ColorChoice) {
+    let on = choice.resolve(io::stdout().is_terminal());
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn style(s: &str, color: &str, on: bool) -> String {
+    if on {
+        format!("{}{}{}", color, s, RESET)
+    } else {
+        s.to_string()
+    }
+}
+
+// `s` in `color` if coloring is enabled.
+pub fn paint(s: &str, color: &str) -> String {
+    style(s, color, enabled())
+}
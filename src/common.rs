@@ -1,12 +1,17 @@
-use crate::value::Value;
+use crate::color::{self, style, CYAN, DIM, YELLOW};
+use crate::value::{Function, Value};
+use std::cell::RefCell;
 use std::mem;
+use std::rc::Rc;
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Op {
     // 1-byte Instructions
     Return,
     Pop,
     LoadTrue,
+    LoadFalse,
 
     Negate,
     IsEqual,
@@ -14,14 +19,29 @@ pub enum Op {
     Subtract,
     Multiply,
     Divide,
+    Modulo,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    GetIter,
+    IterNext,
+    PopHandler,
+    Rethrow,
+    Dup,
+    Swap,
 
     // 2-byte Instructions
     LoadConstant,
+    DefineGlobal,
     SetGlobal,
     GetGlobal,
     SetLocal,
     GetLocal,
     Call,
+    TailCall,
+    MakeList,
+    PopN,
 
     SetUpvalue,
     GetUpvalue,
@@ -30,11 +50,19 @@ pub enum Op {
     Jump,
     AbsJump,
     JumpIfFalse,
+    JumpIfNotNone,
+
+    // 4-byte Instructions
+    PushHandler, // catch address, error kind, 0xff for any or FINALLY
 
     // Vairable Length Instruction
     MakeClosure,
 }
 
+// The error kind byte of a PushHandler for a finally block. It catches
+// any error and gets the message and kind, so Rethrow can raise it again.
+pub const FINALLY: u8 = 0xfe;
+
 impl Op {
     #[inline]
     pub fn from_u8(byte: u8) -> Op {
@@ -42,17 +70,35 @@ impl Op {
     }
 }
 
+// A decoded instruction, see Chunk::decode_at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub op: Op,
+    pub operands: Vec<u32>,
+    pub size: usize, // In bytes, opcode included
+}
+
+// Indexed by name constant, (VM id, global slot).
+pub type GlobalCache = Rc<RefCell<Vec<Option<(usize, usize)>>>>;
+
 #[derive(Clone, Debug)]
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
+    // Kept apart from the constants so small values stay close together.
+    pub functions: Vec<Function>,
+    // The global slot each name constant resolved to, with the id of the VM
+    // it holds for. Shared by the copies of the chunk, see VM::global_slot.
+    pub global_cache: GlobalCache,
 }
 
 impl Chunk {
     pub fn new(code: Vec<u8>, constants: Vec<Value>) -> Chunk {
         Chunk {
-            code: code,
-            constants: constants,
+            code,
+            constants,
+            functions: vec![],
+            global_cache: Rc::new(RefCell::new(vec![])),
         }
     }
 
@@ -72,85 +118,238 @@ impl Chunk {
         self.constants.len() - 1
     }
 
-    pub fn disassemble_at(&self, i: usize) -> (String, usize) {
-        match Op::from_u8(self.code[i]) {
-            // 1-byte Instructions
-            Op::Return => ("return".to_string(), 1),
+    pub fn add_function(&mut self, function: Function) -> usize {
+        self.functions.push(function);
+        self.functions.len() - 1
+    }
+
+    // Decodes the instruction at i. Jumps have their 2-byte target as one
+    // operand, make_closure has the function's index followed by an
+    // (is local, index) pair for each upvalue.
+    pub fn decode_at(&self, i: usize) -> Instruction {
+        let op = Op::from_u8(self.code[i]);
+        let byte = |k: usize| self.code[i + k] as u32;
+        let operands = match op {
             Op::MakeClosure => {
-                let idx = self.code[i + 1];
-                (format!("make_closure {:#04x}", idx), 2)
+                let f = &self.functions[self.code[i + 1] as usize];
+                (1..2 + 2 * f.upvalue_count).map(byte).collect()
             }
-            Op::Pop => ("pop".to_string(), 1),
-            Op::LoadTrue => ("load_true".to_string(), 1),
-
-            Op::Negate => ("negate".to_string(), 1),
-            Op::IsEqual => ("is_equal".to_string(), 1),
-            Op::Add => ("add".to_string(), 1),
-            Op::Subtract => ("subtract".to_string(), 1),
-            Op::Multiply => ("multiply".to_string(), 1),
-            Op::Divide => ("divide".to_string(), 1),
-
-            // 2-byte Instructions
-            Op::LoadConstant => {
-                let idx = self.code[i + 1];
-                let val = &self.constants[idx as usize];
-                (format!("load_constant {:#04x} ({})", idx, val), 2)
+            Op::PushHandler => vec![self.read_byte_double(i + 1) as u32, byte(3)],
+            Op::Jump | Op::AbsJump | Op::JumpIfFalse | Op::JumpIfNotNone => {
+                vec![self.read_byte_double(i + 1) as u32]
             }
+            Op::LoadConstant
+            | Op::DefineGlobal
+            | Op::SetGlobal
+            | Op::GetGlobal
+            | Op::SetLocal
+            | Op::GetLocal
+            | Op::Call
+            | Op::TailCall
+            | Op::MakeList
+            | Op::PopN
+            | Op::SetUpvalue
+            | Op::GetUpvalue => vec![byte(1)],
+            _ => vec![],
+        };
+        let size = match op {
+            Op::Jump | Op::AbsJump | Op::JumpIfFalse | Op::JumpIfNotNone => 3,
+            Op::PushHandler => 4,
+            _ => 1 + operands.len(),
+        };
+        Instruction { op, operands, size }
+    }
 
-            Op::SetGlobal => {
-                let name = &self.constants[self.code[i + 1] as usize];
-                (format!("set_global {}", name), 2)
-            }
+    // The chunk's own code decoded in order, nested functions are left out.
+    pub fn instructions(&self) -> Vec<Instruction> {
+        let mut instructions = vec![];
+        let mut i = 0;
+        while i < self.code.len() {
+            let instruction = self.decode_at(i);
+            i += instruction.size;
+            instructions.push(instruction);
+        }
+        instructions
+    }
 
-            Op::GetGlobal => {
-                let name = &self.constants[self.code[i + 1] as usize];
-                (format!("get_global {}", name), 2)
+    // The most values on the stack at once while running the chunk, starting
+    // with `start` values (a function's arguments). Every path through the
+    // code is followed once, from the first depth it is reached with.
+    pub fn max_stack(&self, start: usize) -> usize {
+        let mut seen = vec![false; self.code.len()];
+        let mut todo = vec![(0, start)];
+        let mut max = start;
+        while let Some((i, depth)) = todo.pop() {
+            if i >= self.code.len() || seen[i] {
+                continue;
             }
+            seen[i] = true;
+            let Instruction { op, operands, size } = self.decode_at(i);
+            let arg = || operands[0] as usize;
+            let (depth, jump) = match op {
+                Op::Return | Op::TailCall | Op::Rethrow => continue,
+                Op::Jump => {
+                    todo.push((i + arg(), depth));
+                    continue;
+                }
+                Op::AbsJump => {
+                    todo.push((arg(), depth));
+                    continue;
+                }
+                Op::JumpIfFalse => (depth - 1, Some((i + arg(), depth - 1))),
+                // Only a None is popped, anything else is kept for the jump.
+                Op::JumpIfNotNone => (depth - 1, Some((i + arg(), depth))),
+                // The handler runs with the error message pushed, and for
+                // a finally its kind as well.
+                Op::PushHandler if operands[1] == FINALLY as u32 => {
+                    (depth, Some((arg(), depth + 2)))
+                }
+                Op::PushHandler => (depth, Some((arg(), depth + 1))),
 
-            Op::SetLocal => {
-                let idx = self.code[i + 1];
-                (format!("set_local {:#04x}", idx), 2)
-            }
+                Op::Pop
+                | Op::DefineGlobal
+                | Op::SetGlobal
+                | Op::SetLocal
+                | Op::SetUpvalue
+                | Op::IsEqual
+                | Op::Add
+                | Op::Subtract
+                | Op::Multiply
+                | Op::Divide
+                | Op::Modulo
+                | Op::Less
+                | Op::Greater
+                | Op::LessEqual
+                | Op::GreaterEqual => (depth - 1, None),
+                Op::Negate | Op::GetIter | Op::PopHandler | Op::Swap => (depth, None),
+                Op::LoadTrue
+                | Op::LoadFalse
+                | Op::Dup
+                | Op::LoadConstant
+                | Op::GetGlobal
+                | Op::GetLocal
+                | Op::GetUpvalue
+                | Op::MakeClosure => (depth + 1, None),
+                Op::IterNext => (depth + 2, None),
+                Op::PopN => (depth - arg(), None),
+                Op::MakeList => (depth + 1 - arg(), None),
+                // The callee and its arguments are replaced by the result.
+                Op::Call => (depth - arg(), None),
+            };
+            max = max.max(depth);
+            todo.push((i + size, depth));
+            todo.extend(jump);
+        }
+        max
+    }
 
-            Op::GetLocal => {
-                let idx = self.code[i + 1];
-                (format!("get_local {:#04x}", idx), 2)
-            }
+    pub fn disassemble_at(&self, i: usize) -> (String, usize) {
+        let Instruction { op, operands, size } = self.decode_at(i);
+        let arg = || operands[0] as usize;
+        let name = || &self.constants[arg()];
+        let text = match op {
+            // 1-byte Instructions
+            Op::Return => "return".to_string(),
+            Op::Pop => "pop".to_string(),
+            Op::LoadTrue => "load_true".to_string(),
+            Op::LoadFalse => "load_false".to_string(),
 
-            Op::Call => {
-                let n_args = self.code[i + 1];
-                (format!("call {:#04x}", n_args), 2)
-            }
+            Op::Negate => "negate".to_string(),
+            Op::IsEqual => "is_equal".to_string(),
+            Op::Add => "add".to_string(),
+            Op::Subtract => "subtract".to_string(),
+            Op::Multiply => "multiply".to_string(),
+            Op::Divide => "divide".to_string(),
+            Op::Modulo => "modulo".to_string(),
+            Op::Less => "less".to_string(),
+            Op::Greater => "greater".to_string(),
+            Op::LessEqual => "less_equal".to_string(),
+            Op::GreaterEqual => "greater_equal".to_string(),
+            Op::GetIter => "get_iter".to_string(),
+            Op::IterNext => "iter_next".to_string(),
+            Op::PopHandler => "pop_handler".to_string(),
+            Op::Rethrow => "rethrow".to_string(),
+            Op::Dup => "dup".to_string(),
+            Op::Swap => "swap".to_string(),
 
-            Op::SetUpvalue => ("set_upvalue".to_string(), 2),
-            Op::GetUpvalue => ("get_upvalue".to_string(), 2),
+            // 2-byte Instructions
+            Op::LoadConstant => format!("load_constant {:#04x} ({})", arg(), name()),
+            Op::DefineGlobal => format!("define_global {}", name()),
+            Op::SetGlobal => format!("set_global {}", name()),
+            Op::GetGlobal => format!("get_global {}", name()),
+            Op::SetLocal => format!("set_local {:#04x}", arg()),
+            Op::GetLocal => format!("get_local {:#04x}", arg()),
+            Op::Call => format!("call {:#04x}", arg()),
+            Op::TailCall => format!("tail_call {:#04x}", arg()),
+            Op::PopN => format!("pop_n {}", arg()),
+            Op::MakeList => format!("make_list {:#04x}", arg()),
+            Op::SetUpvalue => format!("set_upvalue {:#04x}", arg()),
+            Op::GetUpvalue => format!("get_upvalue {:#04x}", arg()),
 
             // 3-byte Instructions
-            Op::Jump => {
-                let offset = self.read_byte_double(i + 1);
-                (format!("jump {:#04x}", offset), 3)
-            }
+            Op::Jump => format!("jump {:#04x}", arg()),
+            Op::AbsJump => format!("abs_jump {:#04x}", arg()),
+            Op::JumpIfFalse => format!("jump_if_false {:#04x}", arg()),
+            Op::JumpIfNotNone => format!("jump_if_not_none {:#04x}", arg()),
 
-            Op::AbsJump => {
-                let offset = self.read_byte_double(i + 1);
-                (format!("abs_jump {:#04x}", offset), 3)
-            }
+            // 4-byte Instructions
+            Op::PushHandler => format!("push_handler {:#04x} {}", arg(), operands[1]),
 
-            Op::JumpIfFalse => {
-                let offset = self.read_byte_double(i + 1);
-                (format!("jump_if_false {:#04x}", offset), 3)
+            // Vairable Length Instruction
+            Op::MakeClosure => {
+                let upvalues: Vec<String> = operands[1..]
+                    .chunks(2)
+                    .map(|up| {
+                        let kind = if up[0] != 0 { "local" } else { "upvalue" };
+                        format!(" {} {}", kind, up[1])
+                    })
+                    .collect();
+                format!(
+                    "make_closure {:#04x} (<function/{}>){}",
+                    arg(),
+                    self.functions[arg()].arity,
+                    upvalues.concat()
+                )
             }
-        }
+        };
+        (text, size)
     }
 
     pub fn disassemble(&self) {
+        print!("{}", self.styled_disassembly(color::enabled()));
+    }
+
+    // The chunk's constants and instructions followed by those of the
+    // functions it makes closures of, each indented under a header.
+    pub fn disassembly(&self) -> String {
+        self.styled_disassembly(false)
+    }
+
+    pub fn styled_disassembly(&self, color: bool) -> String {
+        let mut out = String::new();
+        self.write_disassembly(&mut out, "", color);
+        out
+    }
+
+    fn write_disassembly(&self, out: &mut String, indent: &str, color: bool) {
+        let constants = format!("Constants: {:?}", self.constants);
+        out.push_str(&format!("{}{}\n", indent, style(&constants, DIM, color)));
         let mut i = 0;
-        println!("Constants: {:?}", self.constants);
         while i < self.code.len() {
             let (s, j) = self.disassemble_at(i);
-            println!("| {:#04x} : {}", i, s);
+            let (op, operands) = s.split_once(' ').unwrap_or((&s, ""));
+            let offset = style(&format!("| {:#04x} :", i), DIM, color);
+            let op = style(op, YELLOW, color);
+            let sep = if operands.is_empty() { "" } else { " " };
+            out.push_str(&format!("{}{} {}{}{}\n", indent, offset, op, sep, operands));
             i += j;
         }
+        for (idx, f) in self.functions.iter().enumerate() {
+            let header = format!("Function {:#04x} (<function/{}>):", idx, f.arity);
+            out.push_str(&format!("{}{}\n", indent, style(&header, CYAN, color)));
+            f.chunk
+                .write_disassembly(out, &format!("{}    ", indent), color);
+        }
     }
 }
 
@@ -161,6 +360,7 @@ pub enum Core {
 
     // Higher Values
     Lambda(Vec<String>, Box<Core>),
+    List(Vec<Core>),
 
     // Variable
     Let(String, Box<Core>), // Variable Declaraction
@@ -169,7 +369,11 @@ pub enum Core {
 
     // Control Flow
     If(Box<Core>, Box<Core>, Box<Core>),
+    Coalesce(Box<Core>, Box<Core>),
+    Try(Box<Core>, String, Option<String>, Box<Core>), // try { body } catch (name: Kind) { handler }
+    Finally(Box<Core>, Box<Core>),                     // body finally { cleanup }
     Loop(Box<Core>),
+    For(String, Box<Core>, Box<Core>), // for name in iterable { body }
     Continue,
     Break,
 
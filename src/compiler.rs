@@ -1,5 +1,20 @@
-use crate::common::{Chunk, Core, Op};
-use crate::value::{Closure, Function, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+
+use crate::common::{Chunk, Core, Op, FINALLY};
+use crate::config::MAX_NESTING_DEPTH;
+use crate::value::{Function, Value};
+use crate::vm::ErrorKind;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError(pub String);
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum Upvalue {
@@ -7,6 +22,10 @@ pub enum Upvalue {
     NonLocal(usize),
 }
 
+// Int literals below this share one constant slot per function, they are
+// common enough in loops that each use getting its own slot adds up.
+const SMALL_INTS: usize = 256;
+
 pub struct CCtx {
     pub function: Function,
     locals: Vec<(String, usize, bool)>, // (Name, Depth, isCaptured)
@@ -14,6 +33,16 @@ pub struct CCtx {
     scope_depth: usize,
     continues: Vec<Vec<usize>>,
     breaks: Vec<Vec<usize>>,
+    loops: Vec<(usize, usize)>, // (Locals, Open trys) when each enclosing loop began
+    trys: Vec<(usize, Option<Core>)>, // (Locals, Cleanup of a finally) of each open try
+    small_ints: [Option<u8>; SMALL_INTS], // Constant slot of each small int literal used so far
+    initializing: Vec<(String, usize)>, // (Name, Locals) of each local let whose value is being compiled
+}
+
+impl Default for CCtx {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CCtx {
@@ -25,14 +54,117 @@ impl CCtx {
             scope_depth: 0,
             continues: vec![],
             breaks: vec![],
+            loops: vec![],
+            trys: vec![],
+            small_ints: [None; SMALL_INTS],
+            initializing: vec![],
         }
     }
 }
 
+// A let bound lambda small enough to inline, see Compiler::inline_call.
+struct Inlinable {
+    params: Vec<String>,
+    body: Core,
+    local: Option<(usize, usize)>, // (Context, Local) it is bound to, None for a global
+}
+
 pub struct Compiler {
     pub ctxs: Vec<CCtx>,
     current: usize,
     dbg: bool,
+    depth: usize,
+    max_depth: usize,
+    inline_limit: usize,
+    bindings: HashMap<String, usize>, // How many lets and assignments each name has in the program
+    inlinable: HashMap<String, Inlinable>,
+    tail: bool, // Whether the next expression compiled is the last thing its function does
+    lambda_name: Option<String>, // The name a let is binding the next lambda compiled to
+}
+
+fn ends_with_return(x: &Core) -> bool {
+    match x {
+        Core::Return(_) => true,
+        Core::Block(xs) => xs.last().is_some_and(ends_with_return),
+        _ => false,
+    }
+}
+
+// Size of a body made only of its parameters, literals and operators, which
+// is all that gets inlined. Nothing in such a body can refer to a name at the
+// call site or have an effect besides an error.
+fn inline_size(body: &Core, params: &[String]) -> Option<usize> {
+    let sum = |xs: &[&Core]| -> Option<usize> {
+        xs.iter()
+            .map(|x| inline_size(x, params))
+            .sum::<Option<usize>>()
+            .map(|n| n + 1)
+    };
+    match body {
+        Core::Lit(_) => Some(1),
+        Core::Get(n) if params.contains(n) => Some(1),
+        Core::Call(f, args) if try_arithmetic_op(f).is_some() => {
+            sum(&args.iter().collect::<Vec<_>>())
+        }
+        Core::If(cond, on_true, on_false) => sum(&[cond, on_true, on_false]),
+        Core::Coalesce(value, fallback) => sum(&[value, fallback]),
+        Core::Block(xs) if xs.len() == 1 => inline_size(&xs[0], params),
+        _ => None,
+    }
+}
+
+// The body with each parameter replaced by its argument.
+fn substitute(body: &Core, params: &[String], args: &[Core]) -> Core {
+    let sub = |x: &Core| Box::new(substitute(x, params, args));
+    match body {
+        Core::Get(n) => match params.iter().position(|p| p == n) {
+            Some(i) => args[i].clone(),
+            None => body.clone(),
+        },
+        Core::Call(f, xs) => Core::Call(
+            f.clone(),
+            xs.iter().map(|x| substitute(x, params, args)).collect(),
+        ),
+        Core::If(cond, on_true, on_false) => Core::If(sub(cond), sub(on_true), sub(on_false)),
+        Core::Coalesce(value, fallback) => Core::Coalesce(sub(value), sub(fallback)),
+        Core::Block(xs) => Core::Block(xs.iter().map(|x| substitute(x, params, args)).collect()),
+        x => x.clone(),
+    }
+}
+
+// Counts the lets and assignments of each name, a lambda is only inlined
+// through a name bound once and never changed.
+fn count_bindings(x: &Core, counts: &mut HashMap<String, usize>) {
+    match x {
+        Core::Let(name, value) | Core::Set(name, value) => {
+            *counts.entry(name.clone()).or_default() += 1;
+            count_bindings(value, counts);
+        }
+        Core::Lit(_) | Core::Get(_) | Core::Continue | Core::Break => (),
+        Core::Lambda(_, body) | Core::Loop(body) | Core::Return(body) => {
+            count_bindings(body, counts)
+        }
+        Core::Coalesce(a, b) | Core::Try(a, _, _, b) | Core::Finally(a, b) | Core::For(_, a, b) => {
+            count_bindings(a, counts);
+            count_bindings(b, counts);
+        }
+        Core::If(a, b, c) => {
+            for x in [a, b, c] {
+                count_bindings(x, counts);
+            }
+        }
+        Core::List(xs) | Core::Block(xs) => {
+            for x in xs {
+                count_bindings(x, counts);
+            }
+        }
+        Core::Call(f, xs) => {
+            count_bindings(f, counts);
+            for x in xs {
+                count_bindings(x, counts);
+            }
+        }
+    }
 }
 
 fn try_arithmetic_op(x: &Core) -> Option<Op> {
@@ -43,19 +175,101 @@ fn try_arithmetic_op(x: &Core) -> Option<Op> {
             "-" => Op::Subtract,
             "*" => Op::Multiply,
             "/" => Op::Divide,
+            "%" => Op::Modulo,
+            "<" => Op::Less,
+            ">" => Op::Greater,
+            "<=" => Op::LessEqual,
+            ">=" => Op::GreaterEqual,
             _ => return None,
         });
     }
     None
 }
 
+// The operands and operators of a < b <= c, which reads as a < b and b <= c.
+fn comparison_chain<'a>(f: &Core, args: &'a [Core]) -> Option<(Vec<&'a Core>, Vec<Op>)> {
+    let op = try_arithmetic_op(f).filter(|op| {
+        matches!(
+            op,
+            Op::Less | Op::Greater | Op::LessEqual | Op::GreaterEqual
+        )
+    })?;
+    let [left, right] = args else {
+        return None;
+    };
+    let (mut operands, mut ops) = match left {
+        Core::Call(g, inner) => comparison_chain(g, inner).unwrap_or((vec![left], vec![])),
+        _ => (vec![left], vec![]),
+    };
+    operands.push(right);
+    ops.push(op);
+    Some((operands, ops))
+}
+
 impl Compiler {
     pub fn new(dbg: bool) -> Compiler {
         Compiler {
             ctxs: vec![CCtx::new()],
             current: 0,
             dbg,
+            depth: 0,
+            max_depth: MAX_NESTING_DEPTH,
+            inline_limit: 0,
+            bindings: HashMap::new(),
+            inlinable: HashMap::new(),
+            tail: false,
+            lambda_name: None,
+        }
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    // Calls to lambdas whose body is at most `limit` nodes are replaced by the
+    // body, 0 (the default) turns inlining off.
+    pub fn set_inline_limit(&mut self, limit: usize) {
+        self.inline_limit = limit;
+    }
+
+    // The body to compile in place of calling `f`, when `f` is known to be a
+    // small lambda and the arguments are simple enough to be copied into it.
+    fn inline_call(&self, f: &Core, args: &[Core]) -> Option<Core> {
+        if !args
+            .iter()
+            .all(|x| matches!(x, Core::Lit(_) | Core::Get(_)))
+        {
+            return None;
+        }
+        let (params, body) = match f {
+            Core::Lambda(params, body) => {
+                if inline_size(body, params)? > self.inline_limit {
+                    return None;
+                }
+                (params, &**body)
+            }
+            Core::Get(name) => {
+                let known = self.inlinable.get(name)?;
+                let bound_here = match known.local {
+                    Some((ctx, idx)) => {
+                        ctx == self.current && self.resolve_local(name, ctx) == Some(idx)
+                    }
+                    None => !self
+                        .ctxs
+                        .iter()
+                        .any(|ctx| ctx.locals.iter().any(|(n, _, _)| n == name)),
+                };
+                if !bound_here {
+                    return None;
+                }
+                (&known.params, &known.body)
+            }
+            _ => return None,
+        };
+        if params.len() != args.len() {
+            return None;
         }
+        Some(substitute(body, params, args))
     }
 
     #[inline]
@@ -63,6 +277,19 @@ impl Compiler {
         self.ctxs[self.current].function.chunk.add_constant(x)
     }
 
+    fn add_literal(&mut self, x: &Value) -> u8 {
+        let small = match x {
+            Value::Int(n) if (0..SMALL_INTS as isize).contains(n) => *n as usize,
+            x => return self.add_constant(x.clone()) as u8,
+        };
+        if let Some(idx) = self.ctxs[self.current].small_ints[small] {
+            return idx;
+        }
+        let idx = self.add_constant(x.clone()) as u8;
+        self.ctxs[self.current].small_ints[small] = Some(idx);
+        idx
+    }
+
     #[inline]
     fn add_byte(&mut self, b: u8) {
         self.ctxs[self.current].function.chunk.code.push(b);
@@ -74,7 +301,20 @@ impl Compiler {
         self.add_byte(b2);
     }
 
-    fn resolve_local(&self, name: &String, ctx_i: usize) -> Option<usize> {
+    fn add_pops(&mut self, n: usize) {
+        match n {
+            0 => (),
+            1 => self.add_byte(Op::Pop as u8),
+            n => self.add_bytes(Op::PopN as u8, n as u8),
+        }
+    }
+
+    fn add_none(&mut self) {
+        let idx = self.add_constant(Value::None) as u8;
+        self.add_bytes(Op::LoadConstant as u8, idx);
+    }
+
+    fn resolve_local(&self, name: &str, ctx_i: usize) -> Option<usize> {
         let locals = &self.ctxs[ctx_i].locals;
         for i in (0..locals.len()).rev() {
             let (n, _, _) = &locals[i];
@@ -85,34 +325,36 @@ impl Compiler {
         None
     }
 
-    fn resolve_upvalue(&mut self, name: &String, ctx_i: usize) -> Option<usize> {
-        if self.ctxs.len() <= 1 {
-            return None;
+    fn resolve_upvalue(&mut self, name: &str, ctx_i: usize) -> Option<usize> {
+        if ctx_i == 0 {
+            None
         } else if let Some(idx) = self.resolve_local(name, ctx_i - 1) {
             self.ctxs[ctx_i - 1].locals[idx].2 = true;
             Some(self.add_upvalue(Upvalue::Local(idx), ctx_i))
-        } else if let Some(idx) = self.resolve_upvalue(name, ctx_i - 1) {
-            Some(self.add_upvalue(Upvalue::NonLocal(idx), ctx_i))
         } else {
-            None
+            self.resolve_upvalue(name, ctx_i - 1)
+                .map(|idx| self.add_upvalue(Upvalue::NonLocal(idx), ctx_i))
         }
     }
 
-    fn resolve_global(&self, name: &String) -> Option<usize> {
-        let consts = &self.ctxs[self.current].function.chunk.constants;
-        for i in 0..consts.len() {
-            if let Value::Str(x) = &consts[i] {
-                if x == name {
-                    return Some(i);
-                }
-            }
-        }
-        None
+    fn add_local(&mut self, name: &str, ctx_i: usize) {
+        let depth = self.ctxs[ctx_i].scope_depth;
+        self.ctxs[ctx_i]
+            .locals
+            .push((name.to_string(), depth, false))
     }
 
-    fn add_local(&mut self, name: &String, ctx_i: usize) {
-        let depth = self.ctxs[ctx_i].scope_depth;
-        self.ctxs[ctx_i].locals.push((name.clone(), depth, false))
+    // A value left on the stack while more is compiled, like an argument
+    // before the next one. It takes a slot, so it is counted as a local no
+    // name refers to and the locals declared after it land above it.
+    fn add_temp(&mut self) {
+        self.add_local(" temp", self.current);
+    }
+
+    // Forgets the `n` temps on top, once an instruction has consumed them.
+    fn drop_temps(&mut self, n: usize) {
+        let locals = &mut self.ctxs[self.current].locals;
+        locals.truncate(locals.len() - n);
     }
 
     fn add_upvalue(&mut self, up_insert: Upvalue, ctx_i: usize) -> usize {
@@ -130,43 +372,178 @@ impl Compiler {
         }
         self.ctxs[ctx_i].function.upvalue_count += 1;
         self.ctxs[ctx_i].upvalues.push(up_insert);
-        return self.ctxs[ctx_i].function.upvalue_count - 1;
+        self.ctxs[ctx_i].function.upvalue_count - 1
     }
 
     fn begin_scope(&mut self) {
         self.ctxs[self.current].scope_depth += 1;
     }
 
-    fn end_scope(&mut self) {
+    // Pops the scope's locals off the stack, keeping the scope's value on top
+    // by moving it into the slot of the first local.
+    fn end_scope(&mut self, has_value: bool) {
         self.ctxs[self.current].scope_depth -= 1;
 
+        let mut popped = 0;
         while let Some(x) = self.ctxs[self.current].locals.last() {
             if x.1 <= self.ctxs[self.current].scope_depth {
                 break;
             }
-            // self.add_byte(Op::Pop as u8);
             self.ctxs[self.current].locals.pop();
+            popped += 1;
+        }
+        let (current, n_locals) = (self.current, self.ctxs[self.current].locals.len());
+        self.inlinable.retain(
+            |_, x| !matches!(x.local, Some((ctx, idx)) if ctx == current && idx >= n_locals),
+        );
+
+        if has_value && popped > 0 {
+            let slot = self.ctxs[self.current].locals.len();
+            self.add_bytes(Op::SetLocal as u8, slot as u8);
+            popped -= 1;
+        }
+        self.add_pops(popped);
+    }
+
+    // Starts a loop at the current instruction, returning where it starts.
+    fn begin_loop(&mut self) -> usize {
+        let ctx = &mut self.ctxs[self.current];
+        ctx.continues.push(vec![]);
+        ctx.breaks.push(vec![]);
+        ctx.loops.push((ctx.locals.len(), ctx.trys.len()));
+        ctx.function.chunk.code.len()
+    }
+
+    // Jumps back to the start of the loop, returning the breaks which still
+    // need to be pointed at the loop's exit.
+    fn end_loop(&mut self, loop_start_idx: usize) -> Vec<usize> {
+        self.ctxs[self.current].loops.pop();
+        self.add_byte(Op::AbsJump as u8);
+        self.add_bytes(0xff, 0xff);
+
+        let ctx = &mut self.ctxs[self.current];
+        let k = ctx.function.chunk.code.len() - 2;
+        ctx.function.chunk.write_byte_double(k, loop_start_idx);
+        for continue_jump_idx in ctx.continues.pop().unwrap() {
+            ctx.function
+                .chunk
+                .write_byte_double(continue_jump_idx + 1, loop_start_idx);
+        }
+        ctx.breaks.pop().unwrap()
+    }
+
+    fn patch_breaks(&mut self, breaks: Vec<usize>) {
+        let chunk = &mut self.ctxs[self.current].function.chunk;
+        let loop_exit_idx = chunk.code.len();
+        for break_jump_idx in breaks {
+            chunk.write_byte_double(break_jump_idx + 1, loop_exit_idx);
         }
     }
 
-    fn declare_var(&mut self, name: &String) {
+    // Break and continue leave the loop body early, so they pop the locals
+    // declared and the trys opened inside it themselves.
+    fn pop_loop_locals(&mut self, keyword: &str) -> Result<(), CompileError> {
+        let ctx = &self.ctxs[self.current];
+        let (locals, trys) = match ctx.loops.last() {
+            Some(start) => *start,
+            None => return Err(CompileError(format!("{} outside of a loop", keyword))),
+        };
+        let saved = ctx.locals.clone();
+        self.leave_trys(trys, false)?;
+        self.pop_locals_to(locals, false);
+        self.ctxs[self.current].locals = saved;
+        Ok(())
+    }
+
+    // Pops the trys opened after the first `keep`, running the cleanup of
+    // each finally on the way out with the locals declared inside it gone.
+    // With `carry` the value on top of the stack is kept above the locals.
+    // The caller puts the locals back, the code after it is never reached.
+    fn leave_trys(&mut self, keep: usize, carry: bool) -> Result<(), CompileError> {
+        let trys = self.ctxs[self.current].trys.clone();
+        for (i, (locals, cleanup)) in trys.iter().enumerate().skip(keep).rev() {
+            self.add_byte(Op::PopHandler as u8);
+            if let Some(cleanup) = cleanup {
+                self.pop_locals_to(*locals, carry);
+                // A cleanup leaving early itself only leaves the trys around it.
+                self.ctxs[self.current].trys.truncate(i);
+                self.compile_cleanup(cleanup, if carry { &[" carried"] } else { &[] })?;
+            }
+        }
+        self.ctxs[self.current].trys = trys;
+        Ok(())
+    }
+
+    // Pops the locals above the first `n`, as if their scopes had ended.
+    fn pop_locals_to(&mut self, n: usize, carry: bool) {
+        let ctx = &mut self.ctxs[self.current];
+        let popped = ctx.locals.len() - n;
+        ctx.locals.truncate(n);
+        if carry && popped > 0 {
+            self.add_bytes(Op::SetLocal as u8, n as u8);
+            self.add_pops(popped - 1);
+        } else {
+            self.add_pops(popped);
+        }
+    }
+
+    // `let x = x + 1` in a scope would read a slot that doesn't hold x yet, only
+    // a local declared inside the initializer itself may have the same name.
+    fn check_initialized(&self, name: &str) -> Result<(), CompileError> {
+        let ctx = &self.ctxs[self.current];
+        let Some((_, n_locals)) = ctx.initializing.iter().rev().find(|(n, _)| n == name) else {
+            return Ok(());
+        };
+        match self.resolve_local(name, self.current) {
+            Some(idx) if idx >= *n_locals => Ok(()),
+            _ => Err(CompileError(format!(
+                "Can't read local {} in its own initializer",
+                name
+            ))),
+        }
+    }
+
+    // Remembers `let name = value` if calls to it can be inlined.
+    fn note_inlinable(&mut self, name: &str, value: &Core) {
+        let Core::Lambda(params, body) = value else {
+            return;
+        };
+        if self.inline_limit == 0
+            || self.bindings.get(name) != Some(&1)
+            || inline_size(body, params).is_none_or(|n| n > self.inline_limit)
+        {
+            return;
+        }
+        let ctx = &self.ctxs[self.current];
+        let local = (ctx.scope_depth > 0).then(|| (self.current, ctx.locals.len() - 1));
+        self.inlinable.insert(
+            name.to_string(),
+            Inlinable {
+                params: params.clone(),
+                body: (**body).clone(),
+                local,
+            },
+        );
+    }
+
+    fn declare_var(&mut self, name: &str) {
         // TODO; Check if local already exists
         if self.ctxs[self.current].scope_depth > 0 {
             self.add_local(name, self.current);
         }
     }
 
-    fn define_var(&mut self, name: &String) {
+    fn define_var(&mut self, name: &str) {
         if self.ctxs[self.current].scope_depth == 0 {
             self.ctxs[self.current]
                 .function
                 .chunk
                 .code
-                .push(Op::SetGlobal as u8);
+                .push(Op::DefineGlobal as u8);
             let name_idx = self.ctxs[self.current]
                 .function
                 .chunk
-                .add_constant(Value::Str(name.clone()));
+                .add_constant(Value::Str(name.to_string()));
             self.ctxs[self.current]
                 .function
                 .chunk
@@ -175,10 +552,36 @@ impl Compiler {
         }
     }
 
-    pub fn compile(&mut self, expr: &Core) -> bool {
-        match expr {
+    pub fn compile(&mut self, expr: &Core) -> Result<bool, CompileError> {
+        if self.depth >= self.max_depth {
+            return Err(CompileError(format!(
+                "Exceeded maximum nesting depth of {}",
+                self.max_depth
+            )));
+        }
+        self.depth += 1;
+        let result = self.compile_expr(expr);
+        self.depth -= 1;
+        result
+    }
+
+    // Returns whether the compiled expression left a value on the stack.
+    fn compile_expr(&mut self, expr: &Core) -> Result<bool, CompileError> {
+        // Only the expression it was set for is in tail position, not its parts.
+        let tail = std::mem::take(&mut self.tail);
+        Ok(match expr {
+            Core::Lit(Value::Bool(true)) => {
+                self.add_byte(Op::LoadTrue as u8);
+                true
+            }
+
+            Core::Lit(Value::Bool(false)) => {
+                self.add_byte(Op::LoadFalse as u8);
+                true
+            }
+
             Core::Lit(x) => {
-                let idx = self.add_constant(x.clone()) as u8;
+                let idx = self.add_literal(x);
                 self.add_bytes(Op::LoadConstant as u8, idx);
                 true
             }
@@ -189,13 +592,22 @@ impl Compiler {
                     self.current += 1;
 
                     self.ctxs[self.current].function.arity = args.len();
+                    self.ctxs[self.current].function.name = self.lambda_name.take();
                     self.begin_scope();
                     for arg in args {
                         self.declare_var(arg);
                         self.define_var(arg);
                     }
-                    self.compile(body);
-                    self.add_byte(Op::Return as u8);
+                    // The value of the body's last expression is returned
+                    // implicitly, unless it already ends with a `return`.
+                    self.tail = true;
+                    let has_value = self.compile(body)?;
+                    if !ends_with_return(body) {
+                        if !has_value {
+                            self.add_none();
+                        }
+                        self.add_byte(Op::Return as u8);
+                    }
                     self.done();
 
                     self.current -= 1;
@@ -205,8 +617,10 @@ impl Compiler {
                 let function = sub_ctx.function;
                 let upvalues = sub_ctx.upvalues;
 
-                let f = Value::Function(function);
-                let idx = self.add_constant(f) as u8;
+                let idx = self.ctxs[self.current]
+                    .function
+                    .chunk
+                    .add_function(function) as u8;
 
                 self.add_bytes(Op::MakeClosure as u8, idx);
 
@@ -223,83 +637,136 @@ impl Compiler {
                 true
             }
 
+            Core::List(items) => {
+                for item in items {
+                    self.compile_value(item)?;
+                    self.add_temp();
+                }
+                self.drop_temps(items.len());
+                self.add_bytes(Op::MakeList as u8, items.len() as u8);
+                true
+            }
+
             Core::Call(name, args) => {
+                if self.inline_limit > 0 {
+                    if let Some(body) = self.inline_call(name, args) {
+                        return self.compile(&body);
+                    }
+                }
+                if let Some((operands, ops)) = comparison_chain(name, args) {
+                    if ops.len() > 1 {
+                        return self.compile_chain(&operands, &ops);
+                    }
+                }
                 for arg in args {
-                    self.compile(arg);
+                    self.compile_value(arg)?;
+                    self.add_temp();
                 }
 
                 if let Some(op) = try_arithmetic_op(name) {
                     self.add_byte(op as u8);
+                } else if self.is_tail_position(tail) {
+                    self.compile_value(name)?;
+                    self.add_bytes(Op::TailCall as u8, args.len() as u8);
                 } else {
-                    self.compile(name);
+                    self.compile_value(name)?;
                     self.add_bytes(Op::Call as u8, args.len() as u8);
                 }
+                self.drop_temps(args.len());
                 true
             }
 
+            // Unlike break, return leaves the loop's locals and trys for the
+            // VM to drop along with the frame, only the cleanups of open
+            // finallys are run first. The loop bookkeeping is untouched, the
+            // loop still ends as usual after the return.
             Core::Return(expr) => {
-                self.compile(expr);
+                self.tail = true;
+                self.compile_value(expr)?;
+                let ctx = &self.ctxs[self.current];
+                if ctx.trys.iter().any(|(_, cleanup)| cleanup.is_some()) {
+                    let saved = ctx.locals.clone();
+                    self.leave_trys(0, true)?;
+                    self.ctxs[self.current].locals = saved;
+                }
                 self.add_byte(Op::Return as u8);
                 false
             }
 
             // Variable Access
             Core::Let(name, value) => {
-                self.declare_var(name);
-                self.compile(value);
+                // Lambdas can refer to themselves, everything else is compiled
+                // before the local exists so its stack slot lines up.
+                let recursive = matches!(**value, Core::Lambda(..));
+                if recursive {
+                    self.declare_var(name);
+                    self.note_inlinable(name, value);
+                    self.lambda_name = Some(name.clone());
+                }
+                let local = !recursive && self.ctxs[self.current].scope_depth > 0;
+                if local {
+                    let n_locals = self.ctxs[self.current].locals.len();
+                    self.ctxs[self.current]
+                        .initializing
+                        .push((name.clone(), n_locals));
+                }
+                self.compile_value(value)?;
+                if local {
+                    self.ctxs[self.current].initializing.pop();
+                }
+                if !recursive {
+                    self.declare_var(name);
+                }
                 self.define_var(name);
                 false
             }
 
             Core::Get(name) => {
+                self.check_initialized(name)?;
                 if let Some(idx) = self.resolve_local(name, self.current) {
                     self.add_bytes(Op::GetLocal as u8, idx as u8);
                 } else if let Some(idx) = self.resolve_upvalue(name, self.current) {
                     self.add_bytes(Op::GetUpvalue as u8, idx as u8);
                 } else {
                     let idx = self.add_constant(Value::Str(name.clone())) as u8;
-                    self.add_bytes(Op::GetGlobal as u8, idx as u8);
+                    self.add_bytes(Op::GetGlobal as u8, idx);
                 }
                 true
             }
 
             Core::Set(name, value) => {
-                self.compile(value);
+                self.compile_value(value)?;
 
                 if let Some(idx) = self.resolve_local(name, self.current) {
                     self.add_bytes(Op::SetLocal as u8, idx as u8);
                 } else if let Some(idx) = self.resolve_upvalue(name, self.current) {
                     self.add_bytes(Op::SetUpvalue as u8, idx as u8);
-                } else if let Some(idx) = self.resolve_global(name) {
-                    self.add_bytes(Op::SetGlobal as u8, idx as u8);
                 } else {
-                    panic!("Global not defined")
+                    let idx = self.add_constant(Value::Str(name.clone())) as u8;
+                    self.add_bytes(Op::SetGlobal as u8, idx);
                 }
                 false
             }
 
             Core::Block(exprs) => {
                 self.begin_scope();
-
-                for (i, expr) in exprs.iter().enumerate() {
-                    if self.compile(expr) && !(i == exprs.len() - 1) {
-                        self.add_byte(Op::Pop as u8);
-                    }
-                }
-                self.end_scope();
-                true
+                self.tail = tail;
+                let has_value = self.compile_sequence(exprs)?;
+                self.end_scope(has_value);
+                has_value
             }
 
             Core::If(condition, on_true, on_false) => {
                 // TODO: Implement break in If and Block
 
-                self.compile(condition);
+                self.compile_value(condition)?;
 
                 let then_jump_idx = self.ctxs[self.current].function.chunk.code.len();
                 self.add_byte(Op::JumpIfFalse as u8);
                 self.add_bytes(0xff, 0xff);
 
-                self.compile(on_true);
+                self.tail = tail;
+                self.compile_value(on_true)?;
 
                 let then_end_jump_idx = self.ctxs[self.current].function.chunk.code.len();
 
@@ -312,7 +779,8 @@ impl Compiler {
                     .chunk
                     .write_byte_double(then_jump_idx + 1, k);
 
-                self.compile(on_false);
+                self.tail = tail;
+                self.compile_value(on_false)?;
 
                 let k = self.ctxs[self.current].function.chunk.code.len() - then_end_jump_idx;
                 self.ctxs[self.current]
@@ -322,43 +790,151 @@ impl Compiler {
                 true
             }
 
-            Core::Loop(expr) => {
-                let loop_start_idx = self.ctxs[self.current].function.chunk.code.len();
-                self.ctxs[self.current].continues.push(vec![]);
-                self.ctxs[self.current].breaks.push(vec![]);
+            Core::Coalesce(value, fallback) => {
+                self.compile_value(value)?;
 
-                self.compile(expr);
+                let jump_idx = self.ctxs[self.current].function.chunk.code.len();
+                self.add_byte(Op::JumpIfNotNone as u8);
+                self.add_bytes(0xff, 0xff);
 
-                // Begin Scope here
-                // Pop Here?
-                self.add_byte(Op::AbsJump as u8);
+                self.compile_value(fallback)?;
+
+                let k = self.ctxs[self.current].function.chunk.code.len() - jump_idx;
+                self.ctxs[self.current]
+                    .function
+                    .chunk
+                    .write_byte_double(jump_idx + 1, k);
+                true
+            }
+
+            Core::Try(body, name, kind, handler) => {
+                let kind = match kind {
+                    None => 0xff,
+                    Some(k) => match ErrorKind::from_name(k) {
+                        Some(k) => k as u8,
+                        None => return Err(CompileError(format!("Unknown error kind {}", k))),
+                    },
+                };
+                let handler_idx = self.ctxs[self.current].function.chunk.code.len();
+                self.add_byte(Op::PushHandler as u8);
+                self.add_bytes(0xff, 0xff);
+                self.add_byte(kind);
+
+                let n_locals = self.ctxs[self.current].locals.len();
+                self.ctxs[self.current].trys.push((n_locals, None));
+                self.compile_value(body)?;
+                self.ctxs[self.current].trys.pop();
+                self.add_byte(Op::PopHandler as u8);
+
+                let end_jump_idx = self.ctxs[self.current].function.chunk.code.len();
+                self.add_byte(Op::Jump as u8);
                 self.add_bytes(0xff, 0xff);
 
-                let k = self.ctxs[self.current].function.chunk.code.len() - 2;
+                // The VM unwinds to the stack as it was at PushHandler and
+                // pushes the error message, which becomes the local `name`.
+                let catch_idx = self.ctxs[self.current].function.chunk.code.len();
                 self.ctxs[self.current]
                     .function
                     .chunk
-                    .write_byte_double(k, loop_start_idx);
+                    .write_byte_double(handler_idx + 1, catch_idx);
+                self.begin_scope();
+                self.add_local(name, self.current);
+                self.compile_value(handler)?;
+                self.end_scope(true);
 
-                let loop_exit_idx = self.ctxs[self.current].function.chunk.code.len();
+                let k = self.ctxs[self.current].function.chunk.code.len() - end_jump_idx;
+                self.ctxs[self.current]
+                    .function
+                    .chunk
+                    .write_byte_double(end_jump_idx + 1, k);
+                true
+            }
 
-                for continue_jump_idx in self.ctxs[self.current].continues.pop().unwrap().iter() {
-                    self.ctxs[self.current]
-                        .function
-                        .chunk
-                        .write_byte_double(continue_jump_idx + 1, loop_start_idx);
+            // The cleanup runs after the body whether it finishes or fails,
+            // a failure is raised again once the cleanup is done.
+            Core::Finally(body, cleanup) => {
+                let handler_idx = self.ctxs[self.current].function.chunk.code.len();
+                self.add_byte(Op::PushHandler as u8);
+                self.add_bytes(0xff, 0xff);
+                self.add_byte(FINALLY);
+
+                let n_locals = self.ctxs[self.current].locals.len();
+                self.ctxs[self.current]
+                    .trys
+                    .push((n_locals, Some((**cleanup).clone())));
+                self.compile_value(body)?;
+                self.ctxs[self.current].trys.pop();
+                self.add_byte(Op::PopHandler as u8);
+
+                // The body's value waits under the cleanup as the result.
+                self.compile_cleanup(cleanup, &[" finally"])?;
+                let end_jump_idx = self.ctxs[self.current].function.chunk.code.len();
+                self.add_byte(Op::Jump as u8);
+                self.add_bytes(0xff, 0xff);
+
+                // The VM pushes the error's message and kind for Rethrow.
+                let catch_idx = self.ctxs[self.current].function.chunk.code.len();
+                self.ctxs[self.current]
+                    .function
+                    .chunk
+                    .write_byte_double(handler_idx + 1, catch_idx);
+                self.compile_cleanup(cleanup, &[" error", " kind"])?;
+                self.add_byte(Op::Rethrow as u8);
+
+                let k = self.ctxs[self.current].function.chunk.code.len() - end_jump_idx;
+                self.ctxs[self.current]
+                    .function
+                    .chunk
+                    .write_byte_double(end_jump_idx + 1, k);
+                true
+            }
+
+            Core::Loop(expr) => {
+                let loop_start_idx = self.begin_loop();
+                if self.compile(expr)? {
+                    self.add_byte(Op::Pop as u8);
                 }
+                let breaks = self.end_loop(loop_start_idx);
+                self.patch_breaks(breaks);
+                false
+            }
 
-                for break_jump_idx in self.ctxs[self.current].breaks.pop().unwrap().iter() {
-                    self.ctxs[self.current]
-                        .function
-                        .chunk
-                        .write_byte_double(break_jump_idx + 1, loop_exit_idx);
+            Core::For(name, iterable, body) => {
+                // The iterator lives in a local no source name can refer to.
+                self.begin_scope();
+                self.compile_value(iterable)?;
+                self.add_byte(Op::GetIter as u8);
+                self.add_local(" iter", self.current);
+
+                let loop_start_idx = self.begin_loop();
+                self.add_byte(Op::IterNext as u8);
+                let done_jump_idx = self.ctxs[self.current].function.chunk.code.len();
+                self.add_byte(Op::JumpIfFalse as u8);
+                self.add_bytes(0xff, 0xff);
+
+                self.begin_scope();
+                self.add_local(name, self.current);
+                if self.compile(body)? {
+                    self.add_byte(Op::Pop as u8);
                 }
-                true
+                self.end_scope(false);
+                let breaks = self.end_loop(loop_start_idx);
+
+                // Once done only the None standing in for an item is left over.
+                let k = self.ctxs[self.current].function.chunk.code.len() - done_jump_idx;
+                self.ctxs[self.current]
+                    .function
+                    .chunk
+                    .write_byte_double(done_jump_idx + 1, k);
+                self.add_byte(Op::Pop as u8);
+
+                self.patch_breaks(breaks);
+                self.end_scope(false);
+                false
             }
 
             Core::Continue => {
+                self.pop_loop_locals("continue")?;
                 let continue_jump_idx = self.ctxs[self.current].function.chunk.code.len();
                 self.add_byte(Op::AbsJump as u8);
                 self.add_bytes(0xff, 0xff);
@@ -368,6 +944,7 @@ impl Compiler {
             }
 
             Core::Break => {
+                self.pop_loop_locals("break")?;
                 let break_jump_idx = self.ctxs[self.current].function.chunk.code.len();
                 self.add_byte(Op::AbsJump as u8);
                 self.add_bytes(0xff, 0xff);
@@ -375,16 +952,161 @@ impl Compiler {
                 self.ctxs[self.current].breaks[k].push(break_jump_idx);
                 false
             }
+        })
+    }
+
+    // A call whose value its function returns straight away can reuse the
+    // function's frame. Not at the top level, which has no caller to return
+    // to, nor inside a try, whose handler has to outlive the call.
+    fn is_tail_position(&self, tail: bool) -> bool {
+        tail && self.current > 0 && self.ctxs[self.current].trys.is_empty()
+    }
+
+    // Compiles a chain of comparisons like a < b < c, true when each one is.
+    // Each operand is compiled once, those in the middle are kept in a hidden
+    // local for the next comparison. The first false comparison ends the chain.
+    fn compile_chain(&mut self, operands: &[&Core], ops: &[Op]) -> Result<bool, CompileError> {
+        self.begin_scope();
+        self.add_none();
+        self.add_local(" middle", self.current);
+        let slot = self.ctxs[self.current].locals.len() - 1;
+
+        self.compile_value(operands[0])?;
+        let mut false_jumps = vec![];
+        for (i, op) in ops.iter().enumerate() {
+            self.add_temp();
+            self.compile_value(operands[i + 1])?;
+            self.drop_temps(1);
+            let last = i == ops.len() - 1;
+            if !last {
+                self.add_byte(Op::Dup as u8);
+                self.add_bytes(Op::SetLocal as u8, slot as u8);
+            }
+            self.add_byte(*op as u8);
+            if !last {
+                false_jumps.push(self.ctxs[self.current].function.chunk.code.len());
+                self.add_byte(Op::JumpIfFalse as u8);
+                self.add_bytes(0xff, 0xff);
+                self.add_bytes(Op::GetLocal as u8, slot as u8);
+            }
+        }
+
+        let end_jump_idx = self.ctxs[self.current].function.chunk.code.len();
+        self.add_byte(Op::Jump as u8);
+        self.add_bytes(0xff, 0xff);
+        for idx in false_jumps {
+            let k = self.ctxs[self.current].function.chunk.code.len() - idx;
+            self.ctxs[self.current]
+                .function
+                .chunk
+                .write_byte_double(idx + 1, k);
+        }
+        self.add_byte(Op::LoadFalse as u8);
+
+        let k = self.ctxs[self.current].function.chunk.code.len() - end_jump_idx;
+        self.ctxs[self.current]
+            .function
+            .chunk
+            .write_byte_double(end_jump_idx + 1, k);
+        self.end_scope(true);
+        Ok(true)
+    }
+
+    // Compiles a finally block over values already on the stack, named so
+    // the block's own locals come after them. They are left on the stack.
+    fn compile_cleanup(&mut self, cleanup: &Core, below: &[&str]) -> Result<(), CompileError> {
+        self.begin_scope();
+        for name in below {
+            self.add_local(name, self.current);
+        }
+        if self.compile(cleanup)? {
+            self.add_byte(Op::Pop as u8);
+        }
+        let ctx = &mut self.ctxs[self.current];
+        ctx.scope_depth -= 1;
+        ctx.locals.truncate(ctx.locals.len() - below.len());
+        Ok(())
+    }
+
+    // Compiles an expression whose value is needed, statements like `let` or
+    // an empty block leave none so None stands in for them.
+    fn compile_value(&mut self, expr: &Core) -> Result<(), CompileError> {
+        if !self.compile(expr)? {
+            self.add_none();
+        }
+        Ok(())
+    }
+
+    // Compiles each expression, keeping only the value of the last one.
+    fn compile_sequence(&mut self, exprs: &[Core]) -> Result<bool, CompileError> {
+        let tail = std::mem::take(&mut self.tail);
+        let mut has_value = false;
+        for (i, expr) in exprs.iter().enumerate() {
+            self.tail = tail && i == exprs.len() - 1;
+            has_value = self.compile(expr)?;
+            if has_value && i != exprs.len() - 1 {
+                self.add_byte(Op::Pop as u8);
+            }
+        }
+        Ok(has_value)
+    }
+
+    // Compiles a whole program, returning the value of its last expression.
+    pub fn compile_program(&mut self, expr: &Core) -> Result<Function, CompileError> {
+        if self.inline_limit > 0 {
+            self.bindings.clear();
+            count_bindings(expr, &mut self.bindings);
+        }
+        // The program's own block opens no scope, its lets define globals.
+        let has_value = match expr {
+            Core::Block(exprs) => self.compile_sequence(exprs)?,
+            expr => self.compile(expr)?,
+        };
+        if !has_value {
+            self.add_none();
+        }
+        self.add_byte(Op::Return as u8);
+        Ok(self.done())
+    }
+
+    // Compiles more top level code onto the end of the program compiled so
+    // far, so its globals and constants carry over. Gives the whole program and
+    // the offset its new code starts at. A snippet which fails to compile
+    // leaves the program as it was.
+    pub fn compile_more(&mut self, expr: &Core) -> Result<(Function, usize), CompileError> {
+        // Inlining needs the whole program to know a name is never rebound.
+        let inline_limit = mem::replace(&mut self.inline_limit, 0);
+        let before = self.ctxs[0].function.clone();
+        let compiled = self.compile_program(expr);
+        self.inline_limit = inline_limit;
+        match compiled {
+            Ok(f) => Ok((f, before.chunk.code.len())),
+            Err(e) => {
+                self.ctxs = vec![CCtx::new()];
+                self.ctxs[0].function = before;
+                self.current = 0;
+                self.depth = 0;
+                self.tail = false;
+                Err(e)
+            }
         }
     }
 
     pub fn done(&mut self) -> Function {
         if *self.ctxs[self.current].function.chunk.code.last().unwrap() != (Op::Return as u8) {
-            self.compile(&Core::Lit(Value::None));
+            self.add_none();
             self.add_byte(Op::Return as u8);
         }
+        let ctx = &self.ctxs[self.current];
+        debug_assert!(
+            ctx.loops.is_empty() && ctx.breaks.is_empty() && ctx.continues.is_empty(),
+            "loop bookkeeping left open at the end of a function"
+        );
+        let f = &mut self.ctxs[self.current].function;
+        f.max_stack = f.chunk.max_stack(f.arity);
 
-        if self.dbg {
+        // Lambdas are shown within the program that contains them.
+        if self.dbg && self.current == 0 {
             self.ctxs[self.current].function.chunk.disassemble();
         }
 
@@ -1 +1,11 @@
+// Maximum nesting of blocks, groupings and sub-expressions accepted by the
+// parsers and the compiler before they bail out with an error instead of
+// overflowing the native stack.
+pub const MAX_NESTING_DEPTH: usize = 256;
 
+// Operators the lexer splits out of a run of special characters rather than
+// reading the whole run as one infix name, so `xs |>-1` is `|>` then `-`.
+pub const RESERVED_OPERATORS: &[&str] = &["=>", "|>"];
+
+// Lists and maps nested deeper than this print as [...] or {...}.
+pub const MAX_PRINT_DEPTH: usize = 100;
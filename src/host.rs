@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use crate::value::Value;
+
+// A plain copy of a bonsai value for embedders, free of the shared cells the
+// VM works with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostValue {
+    None,
+    Bool(bool),
+    Int(isize),
+    Float(f64),
+    Str(String),
+    List(Vec<HostValue>),
+    Map(BTreeMap<String, HostValue>),
+    // Anything without a plain data form, e.g. closures, as its debug_repr.
+    // Lists and maps which contain themselves are cut off here as well.
+    Opaque(String),
+}
+
+impl Value {
+    pub fn to_host(&self) -> HostValue {
+        self.to_host_within(&mut vec![])
+    }
+
+    // `within` holds the collections being converted around this value.
+    fn to_host_within(&self, within: &mut Vec<*const ()>) -> HostValue {
+        match self {
+            Value::None => HostValue::None,
+            Value::Bool(x) => HostValue::Bool(*x),
+            Value::Int(x) => HostValue::Int(*x),
+            Value::Float(x) => HostValue::Float(*x),
+            Value::Str(x) => HostValue::Str(x.clone()),
+            Value::List(xs) => {
+                let id = Rc::as_ptr(xs) as *const ();
+                if within.contains(&id) {
+                    return HostValue::Opaque("[...]".to_string());
+                }
+                within.push(id);
+                let items = xs
+                    .borrow()
+                    .iter()
+                    .map(|x| x.to_host_within(within))
+                    .collect();
+                within.pop();
+                HostValue::List(items)
+            }
+            Value::Map(m) => {
+                let id = Rc::as_ptr(m) as *const ();
+                if within.contains(&id) {
+                    return HostValue::Opaque("{...}".to_string());
+                }
+                within.push(id);
+                let items = m
+                    .borrow()
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.to_host_within(within)))
+                    .collect();
+                within.pop();
+                HostValue::Map(items)
+            }
+            x => HostValue::Opaque(x.debug_repr()),
+        }
+    }
+}
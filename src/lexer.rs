@@ -1,3 +1,7 @@
+use std::str::Chars;
+
+use crate::config::RESERVED_OPERATORS;
+
 #[derive(Debug, PartialEq)]
 pub enum Tk {
     // Delimiters
@@ -21,28 +25,138 @@ pub enum Tk {
     LitFloat(f64),
     LitInt(isize),
     LitStr(String),
+    LitChar(char),
 
     // Identifiers
     Name(String),
     NameInfix(String),
+    Operator(String), // One of RESERVED_OPERATORS
 
     // Special
     Eof,
     Error(String),
 }
 
+// Where a token starts in the source, both counting from 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+// The characters of the source, keeping track of the position of the next one.
+#[derive(Clone)]
+struct Cursor<'a> {
+    chars: Chars<'a>,
+    next: Option<char>,
+    span: Span,
+}
+
+impl Cursor<'_> {
+    fn new(source: &str) -> Cursor<'_> {
+        let mut chars = source.chars();
+        Cursor {
+            next: chars.next(),
+            chars,
+            span: Span { line: 1, col: 1 },
+        }
+    }
+
+    fn peek(&self) -> Option<&char> {
+        self.next.as_ref()
+    }
+
+    fn next_if(&mut self, f: impl FnOnce(&char) -> bool) -> Option<char> {
+        match self.peek() {
+            Some(c) if f(c) => self.next(),
+            _ => None,
+        }
+    }
+
+    fn next_if_eq(&mut self, expected: &char) -> Option<char> {
+        self.next_if(|c| c == expected)
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.next?;
+        self.next = self.chars.next();
+        if c == '\n' {
+            self.span.line += 1;
+            self.span.col = 1;
+        } else {
+            self.span.col += 1;
+        }
+        Some(c)
+    }
+}
+
 // Helpers
 #[inline]
 fn is_special(c: char) -> bool {
-    "!@$%^&*-+=|/<>".contains(c)
+    "!@$%^&*-+=|/<>?".contains(c)
+}
+
+// Names start with a letter of any script or an underscore.
+#[inline]
+fn is_name_start(c: char) -> bool {
+    c == '_' || c.is_alphabetic()
+}
+
+#[inline]
+fn is_name_char(c: char) -> bool {
+    c == '_' || c.is_alphanumeric()
+}
+
+// The character an escape like \n stands for, given what follows the \.
+fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '"' | '\'' => Some(c),
+        _ => None,
+    }
+}
+
+// Splits a run of special characters, taking the longest reserved operator
+// while one matches at the front and leaving the rest as a plain infix name.
+fn push_special_run(ts: &mut Vec<Tk>, run: &str) {
+    let mut rest = run;
+    while let Some(op) = RESERVED_OPERATORS
+        .iter()
+        .filter(|op| rest.starts_with(*op))
+        .max_by_key(|op| op.len())
+    {
+        ts.push(Tk::Operator(op.to_string()));
+        rest = &rest[op.len()..];
+    }
+    if !rest.is_empty() {
+        ts.push(Tk::NameInfix(rest.to_string()));
+    }
 }
 
 // Lexer
 pub fn lex(source: String) -> Vec<Tk> {
+    lex_spanned(source).into_iter().map(|(t, _)| t).collect()
+}
+
+// Like lex, with where each token starts. All the tokens split out of a run
+// of special characters start where the run does.
+pub fn lex_spanned(source: String) -> Vec<(Tk, Span)> {
     let mut ts: Vec<Tk> = Vec::new();
-    let mut chars = source.chars().peekable();
+    let mut spans: Vec<Span> = Vec::new();
+    let mut chars = Cursor::new(&source);
 
-    while let Some(c) = chars.next() {
+    loop {
+        let span = chars.span;
+        let Some(c) = chars.next() else {
+            break;
+        };
         match c {
             '(' => ts.push(Tk::LParen),
             ')' => ts.push(Tk::RParen),
@@ -55,6 +169,50 @@ pub fn lex(source: String) -> Vec<Tk> {
             ':' => ts.push(Tk::Colon),
             ';' => ts.push(Tk::Semicolon),
 
+            '`' => {
+                // `name` uses a function as an infix operator
+                let mut name = String::new();
+                let mut ok = false;
+                for c in chars.by_ref() {
+                    match c {
+                        '`' => {
+                            ok = true;
+                            break;
+                        }
+                        c if is_name_char(c) => name.push(c),
+                        _ => break,
+                    }
+                }
+
+                if ok && !name.is_empty() {
+                    ts.push(Tk::NameInfix(format!("`{}`", name)));
+                } else {
+                    ts.push(Tk::Error("Unterminated backtick operator".to_string()))
+                }
+            }
+
+            '0' if matches!(chars.peek(), Some('x' | 'b' | 'o')) => {
+                // 0x, 0b and 0o integers
+                let prefix = chars.next().unwrap();
+                let radix = match prefix {
+                    'x' => 16,
+                    'b' => 2,
+                    _ => 8,
+                };
+                let mut digits = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric()) {
+                    digits.push(c);
+                }
+
+                match isize::from_str_radix(&digits, radix) {
+                    Ok(n) => ts.push(Tk::LitInt(n)),
+                    Err(_) => ts.push(Tk::Error(format!(
+                        "Invalid base {} integer 0{}{}",
+                        radix, prefix, digits
+                    ))),
+                }
+            }
+
             '0'..='9' => {
                 // Parse Number
                 let mut digits = String::from(c);
@@ -62,7 +220,7 @@ pub fn lex(source: String) -> Vec<Tk> {
 
                 while let Some(c) = chars.peek() {
                     match c {
-                        '0'..='9' => digits.push(chars.next().unwrap()),
+                        '0'..='9' | '_' => digits.push(chars.next().unwrap()),
                         '.' => {
                             is_float = true;
                             digits.push(chars.next().unwrap());
@@ -71,12 +229,60 @@ pub fn lex(source: String) -> Vec<Tk> {
                     }
                 }
 
-                if is_float {
-                    let f = digits.parse::<f64>().unwrap();
-                    ts.push(Tk::LitFloat(f));
+                // 2.5e-3, the exponent needs at least one digit
+                let mut missing_exponent = false;
+                if let Some(e) = chars.next_if(|c| matches!(c, 'e' | 'E')) {
+                    is_float = true;
+                    digits.push(e);
+                    digits.extend(chars.next_if(|c| matches!(c, '+' | '-')));
+                    missing_exponent = chars.peek().is_none_or(|c| !c.is_ascii_digit());
+                    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+                        digits.push(c);
+                    }
+                }
+
+                // 1_000_000, a _ goes only between two digits
+                let digit_around = |i: usize| {
+                    let before = digits[..i].ends_with(|c: char| c.is_ascii_digit());
+                    let after = digits[i + 1..].starts_with(|c: char| c.is_ascii_digit());
+                    before && after
+                };
+                if missing_exponent {
+                    ts.push(Tk::Error(format!("Missing exponent in number {}", digits)));
+                } else if !digits.match_indices('_').all(|(i, _)| digit_around(i)) {
+                    ts.push(Tk::Error(format!("Misplaced _ in number {}", digits)));
+                } else {
+                    // Too large for an int, or a float like 1.2.3
+                    let number = digits.replace('_', "");
+                    let token = if is_float {
+                        number.parse::<f64>().map(Tk::LitFloat).ok()
+                    } else {
+                        number.parse::<isize>().map(Tk::LitInt).ok()
+                    };
+                    ts.push(token.unwrap_or_else(|| {
+                        Tk::Error(format!("invalid number literal {}", digits))
+                    }));
+                }
+            }
+
+            '"' if chars.clone().take(2).eq(['"', '"']) => {
+                // """triple quoted""" strings keep newlines and single quotes as is
+                chars.nth(1);
+                let mut s = String::new();
+                let mut ok = false;
+                for c in chars.by_ref() {
+                    s.push(c);
+                    if s.ends_with("\"\"\"") {
+                        s.truncate(s.len() - 3);
+                        ok = true;
+                        break;
+                    }
+                }
+
+                if ok {
+                    ts.push(Tk::LitStr(s));
                 } else {
-                    let f = digits.parse::<isize>().unwrap();
-                    ts.push(Tk::LitInt(f));
+                    ts.push(Tk::Error("Unterminated triple quoted String".to_string()))
                 }
             }
 
@@ -84,32 +290,79 @@ pub fn lex(source: String) -> Vec<Tk> {
                 // Parse String
                 let mut s = String::new();
                 let mut ok = false;
-                while let Some(c) = chars.peek() {
+                let mut bad_escape = None;
+                while let Some(c) = chars.next() {
                     match c {
                         '"' => {
                             ok = true;
-                            chars.next().unwrap();
-                            ts.push(Tk::LitStr(s.clone()));
                             break;
                         }
-                        _ => {
-                            s.push(chars.next().unwrap()) // TODO: Handle NewLine
-                        }
+                        '\\' => match chars.next() {
+                            Some(c) => match unescape(c) {
+                                Some(c) => s.push(c),
+                                None => {
+                                    bad_escape.get_or_insert(c);
+                                }
+                            },
+                            None => break,
+                        },
+                        c => s.push(c),
+                    }
+                }
+
+                match (ok, bad_escape) {
+                    (false, _) => ts.push(Tk::Error("Unterminated String".to_string())),
+                    (true, Some(c)) => {
+                        ts.push(Tk::Error(format!("Unknown escape sequence \\{}", c)))
                     }
+                    (true, None) => ts.push(Tk::LitStr(s)),
                 }
+            }
+
+            '\'' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                ts.push(Tk::Error(
+                    "Character literal must hold one character".to_string(),
+                ))
+            }
 
-                if !ok {
-                    ts.push(Tk::Error("Unterminated String".to_string()))
+            '\'' => {
+                // 'a' or an escape like '\n', holding exactly one character
+                let c = match chars.next() {
+                    Some('\\') => chars.next().map(|c| unescape(c).ok_or(c)),
+                    Some(c) if c != '\n' => Some(Ok(c)),
+                    _ => None,
+                };
+                let closed = chars.next_if_eq(&'\'').is_some();
+                match c {
+                    Some(Ok(c)) if closed => ts.push(Tk::LitChar(c)),
+                    Some(Err(c)) if closed => {
+                        ts.push(Tk::Error(format!("Unknown escape sequence \\{}", c)))
+                    }
+                    _ => {
+                        // Skip the rest of a longer literal up to its quote
+                        let rest: String = chars.clone().take_while(|c| *c != '\n').collect();
+                        let closed = rest.contains('\'');
+                        if closed {
+                            while chars.next() != Some('\'') {}
+                        }
+                        let msg = if closed {
+                            "Character literal must hold one character"
+                        } else {
+                            "Unterminated character literal"
+                        };
+                        ts.push(Tk::Error(msg.to_string()))
+                    }
                 }
             }
 
-            'a'..='z' | 'A'..='Z' | '_' => {
+            c if is_name_start(c) => {
                 // Parse Identifier
                 let mut name = String::from(c);
 
                 while let Some(c) = chars.peek() {
                     match c {
-                        'a'..='z' | 'A'..='Z' | '_' | '0'..='9' => name.push(chars.next().unwrap()),
+                        c if is_name_char(*c) => name.push(chars.next().unwrap()),
                         _ => {
                             ts.push(Tk::Name(name.clone()));
                             break;
@@ -117,24 +370,59 @@ pub fn lex(source: String) -> Vec<Tk> {
                     }
                 }
 
-                if chars.peek() == None {
+                if chars.peek().is_none() {
                     ts.push(Tk::Name(name.clone()));
                 }
             }
 
-            c if is_special(c.clone()) => {
+            '/' if chars.peek() == Some(&'/') => {
+                // Line comment, the newline ending it is still a token
+                while chars.next_if(|c| *c != '\n').is_some() {}
+            }
+
+            '/' if chars.peek() == Some(&'*') => {
+                // Block comment, /* /* nested */ ones */ included
+                chars.next();
+                let mut depth = 1;
+                while depth > 0 {
+                    match (chars.next(), chars.peek()) {
+                        (Some('/'), Some('*')) => {
+                            chars.next();
+                            depth += 1;
+                        }
+                        (Some('*'), Some('/')) => {
+                            chars.next();
+                            depth -= 1;
+                        }
+                        (Some(_), _) => (),
+                        (None, _) => break,
+                    }
+                }
+
+                if depth > 0 {
+                    ts.push(Tk::Error("Unterminated block comment".to_string()))
+                }
+            }
+
+            c if is_special(c) => {
                 let mut name = String::from(c);
-                while let Some(c) = chars.peek() {
+                let mut ahead = chars.clone();
+                while let Some(c) = ahead.next() {
                     match c {
-                        x if is_special(x.clone()) => name.push(chars.next().unwrap()),
-                        _ => {
-                            ts.push(Tk::NameInfix(name.clone()));
-                            break;
-                        }
+                        // A comment right after an operator isn't part of it.
+                        '/' if matches!(ahead.peek(), Some('/' | '*')) => break,
+                        x if is_special(x) => name.push(chars.next().unwrap()),
+                        _ => break,
                     }
                 }
-                if chars.peek() == None {
-                    ts.push(Tk::NameInfix(name.clone()));
+                push_special_run(&mut ts, &name);
+            }
+
+            '\\' => {
+                // A backslash ending a line joins it with the next one
+                while chars.next_if(|c| matches!(c, ' ' | '\t' | '\r')).is_some() {}
+                if chars.next_if_eq(&'\n').is_none() {
+                    ts.push(Tk::Error("Expected a newline after \\".to_string()))
                 }
             }
 
@@ -145,11 +433,11 @@ pub fn lex(source: String) -> Vec<Tk> {
                 ts.push(Tk::NewLine);
             }
 
-            _ => {
-                panic!("Unexpected Character")
-            }
+            c => ts.push(Tk::Error(format!("Unexpected character {:?}", c))),
         }
+        spans.resize(ts.len(), span);
     }
     ts.push(Tk::Eof);
-    ts
+    spans.push(chars.span);
+    ts.into_iter().zip(spans).collect()
 }
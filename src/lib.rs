@@ -0,0 +1,64 @@
+pub mod color;
+pub mod common;
+pub mod compiler;
+pub mod config;
+pub mod host;
+pub mod lexer;
+pub mod macros;
+pub mod native;
+pub mod parser;
+pub mod snapshot;
+pub mod stdlib;
+pub mod value;
+pub mod vm;
+
+use crate::common::Core;
+use crate::lexer::{lex, lex_spanned, Tk};
+use crate::parser::{HigherParser, LowerParser, ParseError, ParserContext};
+
+// Runs both parsing phases over a source string, without compiling it.
+pub fn parse(src: &str, ctx: &ParserContext) -> Result<Core, ParseError> {
+    let mut lower_parser = LowerParser::new(lex_spanned(src.to_string()));
+    lower_parser.set_max_depth(ctx.max_depth());
+    let expr = lower_parser.parse()?;
+    HigherParser::new(vec![expr], ctx).parse()
+}
+
+// Whether the last line of src asks for the next one to finish it, as a line
+// ending in a backslash or in an infix operator after an operand does, see
+// LowerParser. The REPL reads on until this is false.
+pub fn continues(src: &str) -> bool {
+    if src.trim_end().ends_with('\\') {
+        return true;
+    }
+    let operand = |t: &Tk| {
+        matches!(
+            t,
+            Tk::Name(_)
+                | Tk::LitInt(_)
+                | Tk::LitFloat(_)
+                | Tk::LitStr(_)
+                | Tk::LitChar(_)
+                | Tk::RParen
+                | Tk::RSquare
+                | Tk::RBrace
+        )
+    };
+    matches!(
+        &lex(src.to_string())[..],
+        [.., x, Tk::NameInfix(_) | Tk::Operator(_), Tk::Eof] if operand(x)
+    )
+}
+
+// Parses several sources as one program, in order. Their top level
+// expressions are joined so they share globals like a single file.
+pub fn parse_all(srcs: &[&str], ctx: &ParserContext) -> Result<Core, ParseError> {
+    let mut exprs = vec![];
+    for src in srcs {
+        match parse(src, ctx)? {
+            Core::Block(more) => exprs.extend(more),
+            expr => exprs.push(expr),
+        }
+    }
+    Ok(Core::Block(exprs))
+}
@@ -0,0 +1,601 @@
+use std::collections::HashMap;
+
+use crate::common::Core;
+use crate::parser::{
+    Expr, HigherParser, MacroRuleInfix, MacroRulePrefix, ParseError, ParserContext,
+};
+use crate::value::Value;
+
+// The built-in syntax of bonsai, see ParserContext::default_bonsai.
+
+// Ordered from the tightest binding operator to the loosest.
+pub fn infix_operators() -> Vec<String> {
+    vec![
+        "".to_string(),
+        "`".to_string(),
+        "/".to_string(),
+        "*".to_string(),
+        "%".to_string(),
+        "-".to_string(),
+        "+".to_string(),
+        "<".to_string(),
+        ">".to_string(),
+        "<=".to_string(),
+        ">=".to_string(),
+        "==".to_string(),
+        "??".to_string(),
+        "->".to_string(),
+        "=".to_string(),
+    ]
+}
+
+// `start to stop` and `start to stop step n` iterate a range, anything else
+// is an expression giving the iterable.
+fn parse_iterable(ctx: &ParserContext, iterable: &[Expr]) -> Result<Core, ParseError> {
+    let to = Expr::Name("to".to_string());
+    let step = Expr::Name("step".to_string());
+    let Some(to_idx) = iterable.iter().position(|x| *x == to) else {
+        return HigherParser::new(iterable.to_vec(), ctx).parse();
+    };
+    let (start, rest) = (&iterable[..to_idx], &iterable[to_idx + 1..]);
+    let (stop, step) = match rest.iter().position(|x| *x == step) {
+        Some(step_idx) => (&rest[..step_idx], Some(&rest[step_idx + 1..])),
+        None => (rest, None),
+    };
+
+    let mut args = vec![];
+    for part in [Some(start), Some(stop), step].into_iter().flatten() {
+        if part.is_empty() {
+            return Err(ParseError(
+                "Expected start to stop or start to stop step n".to_string(),
+            ));
+        }
+        args.push(HigherParser::new(part.to_vec(), ctx).parse()?);
+    }
+    Ok(Core::Call(Box::new(Core::Get("range".to_string())), args))
+}
+
+// if cond then on_true else on_false, where then can be left out before a
+// block: if cond { on_true } else { on_false }. Without else the if gives 0.
+fn parse_if(ctx: &ParserContext, body: &[Expr]) -> Result<Core, ParseError> {
+    let then = Expr::Name("then".to_string());
+    let else_ = Expr::Name("else".to_string());
+    let (head, on_false) = match body.iter().position(|x| *x == else_) {
+        Some(i) => (&body[..i], Some(&body[i + 1..])),
+        None => (body, None),
+    };
+    let (cond, on_true) = match head.iter().position(|x| *x == then) {
+        Some(i) => (&head[..i], &head[i + 1..]),
+        None => match head {
+            [cond @ .., block @ Expr::Block(_)] => (cond, std::slice::from_ref(block)),
+            _ => (&head[..0], &head[..0]),
+        },
+    };
+    if cond.is_empty() || on_true.is_empty() || on_false.is_some_and(|x| x.is_empty()) {
+        return Err(ParseError(
+            "Expected if cond then body else body or if cond { body } else { body }".to_string(),
+        ));
+    }
+
+    let on_false = match on_false {
+        Some(on_false) => HigherParser::new(on_false.to_vec(), ctx).parse()?,
+        None => Core::Lit(Value::Int(0)),
+    };
+    Ok(Core::If(
+        Box::new(HigherParser::new(cond.to_vec(), ctx).parse()?),
+        Box::new(HigherParser::new(on_true.to_vec(), ctx).parse()?),
+        Box::new(on_false),
+    ))
+}
+
+// if let x = value then on_some else on_none: on_some runs with x bound to
+// value unless it is None, on_none runs otherwise and gives None if left out.
+fn parse_if_let(ctx: &ParserContext, name: &str, rest: &[Expr]) -> Result<Core, ParseError> {
+    let then = Expr::Name("then".to_string());
+    let else_ = Expr::Name("else".to_string());
+    let (value, on_some, on_none) = match rest.iter().position(|x| *x == then) {
+        Some(i) => match &rest[i + 1..] {
+            [on_some] => (&rest[..i], on_some, None),
+            [on_some, kw, on_none] if *kw == else_ => (&rest[..i], on_some, Some(on_none)),
+            _ => (&rest[..0], &then, None),
+        },
+        None => (&rest[..0], &then, None),
+    };
+    if value.is_empty() {
+        return Err(ParseError(
+            "Expected if let name = value then body else body".to_string(),
+        ));
+    }
+
+    let on_none = match on_none {
+        Some(x) => HigherParser::new(vec![x.clone()], ctx).parse()?,
+        None => Core::Lit(Value::None),
+    };
+    let is_none = Core::Call(
+        Box::new(Core::Get("==".to_string())),
+        vec![Core::Get(name.to_string()), Core::Lit(Value::None)],
+    );
+    Ok(Core::Block(vec![
+        Core::Let(
+            name.to_string(),
+            Box::new(HigherParser::new(value.to_vec(), ctx).parse()?),
+        ),
+        Core::If(
+            Box::new(is_none),
+            Box::new(on_none),
+            Box::new(HigherParser::new(vec![on_some.clone()], ctx).parse()?),
+        ),
+    ]))
+}
+
+// switch value { case 1: body; case -2: body; default: body }: runs the body
+// of the case equal to value or the default one, giving None if there is no
+// default. Cases don't fall through, statements after a case's line belong to
+// it until the next case.
+fn parse_switch(ctx: &ParserContext, value: &[Expr], cases: &[Expr]) -> Result<Core, ParseError> {
+    let err = || ParseError("Expected switch value { case n: body; default: body }".to_string());
+    if value.is_empty() {
+        return Err(err());
+    }
+
+    // None labels the default case.
+    let mut arms: Vec<(Option<isize>, Vec<Core>)> = vec![];
+    for stmt in cases {
+        let parts = match stmt {
+            Expr::FExpr(parts) => &parts[..],
+            x => std::slice::from_ref(x),
+        };
+        let (label, body) = match parts {
+            [Expr::Name(kw), Expr::LitInt(n), Expr::NameInfix(c), body @ ..]
+                if kw == "case" && c == ":" =>
+            {
+                (Some(*n), body)
+            }
+            [Expr::Name(kw), Expr::NameInfix(minus), Expr::LitInt(n), Expr::NameInfix(c), body @ ..]
+                if kw == "case" && minus == "-" && c == ":" =>
+            {
+                (Some(-*n), body)
+            }
+            [Expr::Name(kw), Expr::NameInfix(c), body @ ..] if kw == "default" && c == ":" => {
+                (None, body)
+            }
+            [Expr::Name(kw), ..] if kw == "case" || kw == "default" => return Err(err()),
+            stmt => {
+                let Some((_, body)) = arms.last_mut() else {
+                    return Err(err());
+                };
+                body.push(HigherParser::new(stmt.to_vec(), ctx).parse()?);
+                continue;
+            }
+        };
+        if arms.iter().any(|(x, _)| *x == label) {
+            return Err(ParseError(match label {
+                Some(n) => format!("Duplicate case {} in switch", n),
+                None => "Duplicate default in switch".to_string(),
+            }));
+        }
+        let mut stmts = vec![];
+        if !body.is_empty() {
+            stmts.push(HigherParser::new(body.to_vec(), ctx).parse()?);
+        }
+        arms.push((label, stmts));
+    }
+
+    // The value is kept in a local named after the keyword, which no
+    // program can refer to.
+    let mut chain = Core::Lit(Value::None);
+    if let Some((_, body)) = arms.iter().find(|(x, _)| x.is_none()) {
+        chain = Core::Block(body.clone());
+    }
+    for (label, body) in arms.into_iter().rev() {
+        if let Some(n) = label {
+            let is_label = Core::Call(
+                Box::new(Core::Get("==".to_string())),
+                vec![Core::Get("switch".to_string()), Core::Lit(Value::Int(n))],
+            );
+            chain = Core::If(
+                Box::new(is_label),
+                Box::new(Core::Block(body)),
+                Box::new(chain),
+            );
+        }
+    }
+    Ok(Core::Block(vec![
+        Core::Let(
+            "switch".to_string(),
+            Box::new(HigherParser::new(value.to_vec(), ctx).parse()?),
+        ),
+        chain,
+    ]))
+}
+
+fn and(x: Core, y: Core) -> Core {
+    Core::If(
+        Box::new(x),
+        Box::new(y),
+        Box::new(Core::Lit(Value::Bool(false))),
+    )
+}
+
+fn is_equal(x: Core, y: Core) -> Core {
+    Core::Call(Box::new(Core::Get("==".to_string())), vec![x, y])
+}
+
+fn call_native(name: &str, args: Vec<Core>) -> Core {
+    Core::Call(Box::new(Core::Get(name.to_string())), args)
+}
+
+// The condition under which `value` matches `pattern`, pushing the names the
+// pattern binds along with where in `value` they are found.
+fn parse_pattern(
+    pattern: &[Expr],
+    value: Core,
+    bindings: &mut Vec<(String, Core)>,
+) -> Result<Core, ParseError> {
+    let literal = |x: Value| Ok(is_equal(value.clone(), Core::Lit(x)));
+    match pattern {
+        [Expr::Name(n)] if n == "_" => Ok(Core::Lit(Value::Bool(true))),
+        [Expr::Name(n)] if n == "none" => literal(Value::None),
+        [Expr::Tuple(xs)] if xs.is_empty() => literal(Value::None),
+        [Expr::Name(n)] => {
+            if bindings.iter().any(|(x, _)| x == n) {
+                return Err(ParseError(format!("{} is bound twice in a pattern", n)));
+            }
+            bindings.push((n.clone(), value));
+            Ok(Core::Lit(Value::Bool(true)))
+        }
+        [Expr::LitInt(n)] => literal(Value::Int(*n)),
+        [Expr::NameInfix(minus), Expr::LitInt(n)] if minus == "-" => literal(Value::Int(-*n)),
+        [Expr::LitFloat(x)] => literal(Value::Float(*x)),
+        [Expr::NameInfix(minus), Expr::LitFloat(x)] if minus == "-" => literal(Value::Float(-*x)),
+        [Expr::LitStr(x)] => literal(Value::Str(x.clone())),
+        [Expr::FExpr(parts)] => parse_pattern(parts, value, bindings),
+
+        // (a, b) and [a, b] match lists of that length element by element.
+        [Expr::Tuple(xs)] | [Expr::List(xs)] => {
+            let mut conds = vec![
+                is_equal(
+                    call_native("type", vec![value.clone()]),
+                    Core::Lit(Value::Str("list".to_string())),
+                ),
+                is_equal(
+                    call_native("len", vec![value.clone()]),
+                    Core::Lit(Value::Int(xs.len() as isize)),
+                ),
+            ];
+            for (i, x) in xs.iter().enumerate() {
+                let item = call_native(
+                    "nth",
+                    vec![value.clone(), Core::Lit(Value::Int(i as isize))],
+                );
+                conds.push(parse_pattern(std::slice::from_ref(x), item, bindings)?);
+            }
+            Ok(conds
+                .into_iter()
+                .rev()
+                .reduce(|acc, x| and(x, acc))
+                .unwrap())
+        }
+        _ => Err(ParseError(
+            "Expected a literal, name, _ or (pattern, ...) as a pattern".to_string(),
+        )),
+    }
+}
+
+// match value { pattern -> body; ... }: runs the body of the first arm whose
+// pattern matches with the names it binds in scope, giving None if none do.
+fn parse_match(ctx: &ParserContext, value: &[Expr], arms: &[Expr]) -> Result<Core, ParseError> {
+    let err = || ParseError("Expected match value { pattern -> body; ... }".to_string());
+    if value.is_empty() {
+        return Err(err());
+    }
+
+    // As in switch, the value lives in a local named after the keyword.
+    let mut parsed = vec![];
+    for arm in arms {
+        let Expr::FExpr(parts) = arm else {
+            return Err(err());
+        };
+        let arrow = Expr::NameInfix("->".to_string());
+        let Some(i) = parts.iter().position(|x| *x == arrow) else {
+            return Err(err());
+        };
+        if i == 0 || i == parts.len() - 1 {
+            return Err(err());
+        }
+        let mut bindings = vec![];
+        let cond = parse_pattern(&parts[..i], Core::Get("match".to_string()), &mut bindings)?;
+        let mut body: Vec<Core> = bindings
+            .into_iter()
+            .map(|(name, x)| Core::Let(name, Box::new(x)))
+            .collect();
+        body.push(HigherParser::new(parts[i + 1..].to_vec(), ctx).parse()?);
+        parsed.push((cond, body));
+    }
+
+    let mut chain = Core::Lit(Value::None);
+    for (cond, body) in parsed.into_iter().rev() {
+        chain = Core::If(Box::new(cond), Box::new(Core::Block(body)), Box::new(chain));
+    }
+    // Tuples aren't values, matching on (a, b) matches on the list [a, b]
+    // the same patterns take apart.
+    let value = match value {
+        [Expr::Tuple(xs)] if !xs.is_empty() => vec![Expr::List(xs.clone())],
+        _ => value.to_vec(),
+    };
+    Ok(Core::Block(vec![
+        Core::Let(
+            "match".to_string(),
+            Box::new(HigherParser::new(value, ctx).parse()?),
+        ),
+        chain,
+    ]))
+}
+
+// while let x = value { body }: evaluates value before each run of the body
+// with x bound to it, stopping once it is None.
+fn parse_while_let(ctx: &ParserContext, name: &str, rest: &[Expr]) -> Result<Core, ParseError> {
+    let [value @ .., body @ Expr::Block(_)] = rest else {
+        return Err(ParseError(
+            "Expected while let name = value { body }".to_string(),
+        ));
+    };
+    if value.is_empty() {
+        return Err(ParseError(
+            "Expected while let name = value { body }".to_string(),
+        ));
+    }
+    Ok(Core::Loop(Box::new(Core::Block(vec![
+        Core::Let(
+            name.to_string(),
+            Box::new(HigherParser::new(value.to_vec(), ctx).parse()?),
+        ),
+        Core::If(
+            Box::new(is_equal(
+                Core::Get(name.to_string()),
+                Core::Lit(Value::None),
+            )),
+            Box::new(Core::Break),
+            Box::new(Core::Lit(Value::None)),
+        ),
+        HigherParser::new(vec![body.clone()], ctx).parse()?,
+    ]))))
+}
+
+// try { body } catch e { handler }, see the try macro.
+fn parse_try(ctx: &ParserContext, body: &[Expr]) -> Result<Core, ParseError> {
+    if let [body @ Expr::Block(_), Expr::Name(kw), caught, handler @ Expr::Block(_)] = body {
+        let caught = match caught {
+            Expr::Name(name) => Some((name.clone(), None)),
+            Expr::FExpr(parts) => match &parts[..] {
+                [Expr::Name(name), Expr::NameInfix(colon), Expr::Name(kind)] if colon == ":" => {
+                    Some((name.clone(), Some(kind.clone())))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        if let (true, Some((name, kind))) = (kw == "catch", caught) {
+            return Ok(Core::Try(
+                Box::new(HigherParser::new(vec![body.clone()], ctx).parse()?),
+                name,
+                kind,
+                Box::new(HigherParser::new(vec![handler.clone()], ctx).parse()?),
+            ));
+        }
+    }
+    Err(ParseError(
+        "Expected try { body } catch name { handler }".to_string(),
+    ))
+}
+
+pub fn prefix_macros() -> HashMap<String, MacroRulePrefix> {
+    let mut prefix_macros = HashMap::new();
+
+    let prefix_return_macro: MacroRulePrefix = Box::new(|ctx, expr| {
+        Ok(Core::Return(Box::new(
+            HigherParser::new(expr.clone(), ctx).parse()?,
+        )))
+    });
+
+    let prefix_break_macro: MacroRulePrefix = Box::new(|_, _| Ok(Core::Break));
+
+    let prefix_continue_macro: MacroRulePrefix = Box::new(|_, _| Ok(Core::Continue));
+
+    let prefix_if_macro: MacroRulePrefix = Box::new(|ctx, body| parse_if(ctx, body));
+
+    let prefix_loop_macro: MacroRulePrefix = Box::new(|ctx, body| {
+        Ok(Core::Loop(Box::new(
+            HigherParser::new(body.clone(), ctx).parse()?,
+        )))
+    });
+
+    // for x in xs { body }
+    let prefix_for_macro: MacroRulePrefix = Box::new(|ctx, body| {
+        if let [Expr::Name(name), Expr::Name(kw), iterable @ .., block @ Expr::Block(_)] = &body[..]
+        {
+            if kw == "in" && !iterable.is_empty() {
+                return Ok(Core::For(
+                    name.clone(),
+                    Box::new(parse_iterable(ctx, iterable)?),
+                    Box::new(HigherParser::new(vec![block.clone()], ctx).parse()?),
+                ));
+            }
+        }
+        Err(ParseError(
+            "Expected for name in iterable { body }".to_string(),
+        ))
+    });
+
+    // try { body } catch e { handler }: e is the error message.
+    // try { body } catch (e: Kind) { handler } only catches errors of that kind.
+    // Either can end in finally { cleanup }, which runs however the rest ends.
+    let prefix_try_macro: MacroRulePrefix = Box::new(|ctx, body| {
+        if let [rest @ .., Expr::Name(kw), cleanup @ Expr::Block(_)] = &body[..] {
+            if kw == "finally" {
+                let body = match rest {
+                    [body @ Expr::Block(_)] => {
+                        HigherParser::new(vec![body.clone()], ctx).parse()?
+                    }
+                    rest => parse_try(ctx, rest)?,
+                };
+                return Ok(Core::Finally(
+                    Box::new(body),
+                    Box::new(HigherParser::new(vec![cleanup.clone()], ctx).parse()?),
+                ));
+            }
+        }
+        parse_try(ctx, body)
+    });
+
+    // do { body } while cond: the condition is checked after the body, so the
+    // body always runs at least once.
+    let prefix_do_macro: MacroRulePrefix = Box::new(|ctx, body| {
+        if body.len() >= 3 && body[1] == Expr::Name("while".to_string()) {
+            let cond = HigherParser::new(body[2..].to_vec(), ctx).parse()?;
+            return Ok(Core::Loop(Box::new(Core::Block(vec![
+                HigherParser::new(vec![body[0].clone()], ctx).parse()?,
+                Core::If(
+                    Box::new(cond),
+                    Box::new(Core::Lit(Value::None)),
+                    Box::new(Core::Break),
+                ),
+            ]))));
+        }
+        Err(ParseError(
+            "Expected do { body } while condition".to_string(),
+        ))
+    });
+
+    // unless cond then body: if with the branches swapped.
+    let prefix_unless_macro: MacroRulePrefix = Box::new(|ctx, body| {
+        if body.len() == 3 && body[1] == Expr::Name("then".to_string()) {
+            return Ok(Core::If(
+                Box::new(HigherParser::new(vec![body[0].clone()], ctx).parse()?),
+                Box::new(Core::Lit(Value::None)),
+                Box::new(HigherParser::new(vec![body[2].clone()], ctx).parse()?),
+            ));
+        }
+        Err(ParseError(
+            "Expected unless condition then body".to_string(),
+        ))
+    });
+
+    // until cond { body }: checks the condition before each run of the body.
+    let prefix_until_macro: MacroRulePrefix = Box::new(|ctx, body| {
+        if let [cond @ .., block @ Expr::Block(_)] = &body[..] {
+            if !cond.is_empty() {
+                return Ok(Core::Loop(Box::new(Core::Block(vec![
+                    Core::If(
+                        Box::new(HigherParser::new(cond.to_vec(), ctx).parse()?),
+                        Box::new(Core::Break),
+                        Box::new(Core::Lit(Value::None)),
+                    ),
+                    HigherParser::new(vec![block.clone()], ctx).parse()?,
+                ]))));
+            }
+        }
+        Err(ParseError("Expected until condition { body }".to_string()))
+    });
+
+    let prefix_switch_macro: MacroRulePrefix = Box::new(|ctx, body| match &body[..] {
+        [value @ .., Expr::Block(cases)] => parse_switch(ctx, value, cases),
+        _ => Err(ParseError(
+            "Expected switch value { case n: body; default: body }".to_string(),
+        )),
+    });
+
+    let prefix_match_macro: MacroRulePrefix = Box::new(|ctx, body| match &body[..] {
+        [value @ .., Expr::Block(arms)] => parse_match(ctx, value, arms),
+        _ => Err(ParseError(
+            "Expected match value { pattern -> body; ... }".to_string(),
+        )),
+    });
+
+    prefix_macros.insert("return".to_string(), prefix_return_macro);
+    prefix_macros.insert("continue".to_string(), prefix_continue_macro);
+    prefix_macros.insert("break".to_string(), prefix_break_macro);
+    prefix_macros.insert("if".to_string(), prefix_if_macro);
+    prefix_macros.insert("loop".to_string(), prefix_loop_macro);
+    prefix_macros.insert("do".to_string(), prefix_do_macro);
+    prefix_macros.insert("try".to_string(), prefix_try_macro);
+    prefix_macros.insert("for".to_string(), prefix_for_macro);
+    prefix_macros.insert("unless".to_string(), prefix_unless_macro);
+    prefix_macros.insert("until".to_string(), prefix_until_macro);
+    prefix_macros.insert("switch".to_string(), prefix_switch_macro);
+    prefix_macros.insert("match".to_string(), prefix_match_macro);
+    prefix_macros
+}
+
+pub fn infix_macros() -> HashMap<String, MacroRuleInfix> {
+    let mut infix_macros = HashMap::new();
+
+    let infix_lambda_macro: MacroRuleInfix = Box::new(|_, ctx, args, body| {
+        let params = args
+            .iter()
+            .map(|x| match x {
+                Expr::Name(n) => Ok(n.clone()),
+                _ => Err(ParseError("Expected parameter names before ->".to_string())),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Core::Lambda(
+            params,
+            Box::new(Core::Block(vec![
+                HigherParser::new(body.clone(), ctx).parse()?
+            ])),
+        ))
+    });
+
+    let infix_assign_macro: MacroRuleInfix = Box::new(|_, ctx, vars, value| {
+        // `=` binds loosest so `if let x = value then ...` and
+        // `while let x = value { ... }` split here first.
+        if let [Expr::Name(kw), Expr::Name(kw_let), Expr::Name(name)] = &vars[..] {
+            match (kw.as_str(), kw_let.as_str()) {
+                ("if", "let") => return parse_if_let(ctx, name, value),
+                ("while", "let") => return parse_while_let(ctx, name, value),
+                _ => (),
+            }
+        }
+        let err = || ParseError("Expected let name = value or name = value".to_string());
+        if vars.len() > 2 {
+            Err(err())
+        } else if let Some(Expr::Name(n)) = vars.last() {
+            // `x = y = 5` assigns right to left and the chain yields the assigned value.
+            let chained = value.contains(&Expr::NameInfix("=".to_string()));
+            let mut value = HigherParser::new(value.clone(), ctx).parse()?;
+            if let Core::Set(inner, _) = &value {
+                let inner = Core::Get(inner.clone());
+                value = Core::Block(vec![value, inner]);
+            }
+            let value = Box::new(value);
+
+            if vars.len() == 1 && chained {
+                Ok(Core::Block(vec![
+                    Core::Set(n.clone(), value),
+                    Core::Get(n.clone()),
+                ]))
+            } else if vars.len() == 1 {
+                Ok(Core::Set(n.clone(), value))
+            } else if matches!(&vars[0], Expr::Name(l) if l == "let") {
+                Ok(Core::Let(n.clone(), value))
+            } else {
+                Err(err())
+            }
+        } else {
+            Err(err())
+        }
+    });
+
+    // a ?? b: a unless it is none, b is only evaluated when needed.
+    let infix_coalesce_macro: MacroRuleInfix = Box::new(|_, ctx, value, fallback| {
+        Ok(Core::Coalesce(
+            Box::new(HigherParser::new(value.clone(), ctx).parse()?),
+            Box::new(HigherParser::new(fallback.clone(), ctx).parse()?),
+        ))
+    });
+
+    infix_macros.insert("->".to_string(), infix_lambda_macro);
+    infix_macros.insert("=".to_string(), infix_assign_macro);
+    infix_macros.insert("??".to_string(), infix_coalesce_macro);
+    infix_macros
+}
@@ -1,210 +1,233 @@
-use std::collections::HashMap;
 use std::io::{self, BufRead, Write};
-use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, fs};
 
-mod common;
-mod compiler;
-mod config;
-mod lexer;
-mod native;
-mod parser;
-mod value;
-mod vm;
-
-use crate::common::{Chunk, Core, Op};
-use crate::compiler::Compiler;
-use crate::lexer::lex;
-use crate::native::FFI;
-use crate::parser::{
-    Expr, HigherParser, LowerParser, MacroRuleInfix, MacroRulePrefix, ParserContext,
-};
-use crate::value::{Closure, Function, Value};
-use crate::vm::VM;
-
-fn repl(ctx: &ParserContext, ffi: &FFI, dbg: bool) {
+use bonsai::color::{self, ColorChoice, GREEN, RED};
+use bonsai::compiler::Compiler;
+use bonsai::native::FFI;
+use bonsai::parser::ParserContext;
+use bonsai::stdlib;
+use bonsai::value::{Closure, Function, Value};
+use bonsai::vm::{VMResult, VM};
+
+// Settings from the command line flags.
+struct Options {
+    dbg: bool,
+    stats: bool,
+    inline_limit: usize,
+    strict_numbers: bool,
+    memory_limit: Option<usize>,
+    join: bool,
+    check: bool,
+}
+
+fn repl(ctx: &ParserContext, ffi: &FFI, opts: &Options) {
+    let dbg = opts.dbg;
+    // One program grows line by line so globals carry over between lines.
+    let mut cc = Compiler::new(dbg);
+    cc.set_max_depth(ctx.max_depth());
+    let mut vm: Option<VM> = None;
+
     let stdin = io::stdin();
     loop {
-        let line = {
-            print!(">> ");
+        let mut line = String::new();
+        let mut prompt = ">>";
+        loop {
+            print!("{} ", color::paint(prompt, GREEN));
             io::stdout().flush().unwrap();
-            let mut iter = stdin.lock().lines();
-            iter.next().unwrap().unwrap()
-        };
+            match stdin.lock().lines().next() {
+                Some(more) => line.push_str(&more.unwrap()),
+                None if line.is_empty() => return,
+                None => break,
+            }
+            if !bonsai::continues(&line) {
+                break;
+            }
+            line.push('\n');
+            prompt = "..";
+        }
 
-        run("".to_string(), line, &ctx, &ffi, dbg);
-    }
-}
+        let core_expr = match bonsai::parse(&line, ctx) {
+            Ok(core_expr) => core_expr,
+            Err(e) => {
+                eprintln!("{} {}", color::paint("Parse Error:", RED), e);
+                continue;
+            }
+        };
+        if dbg {
+            println!("High Parse: {:?}", core_expr);
+        }
 
-fn run(fname: String, content: String, ctx: &ParserContext, ffi: &FFI, dbg: bool) {
-    if fname.len() > 0 {
-        println!("Running {}", fname);
-        println!("---");
-    }
+        let (f, start) = match cc.compile_more(&core_expr) {
+            Ok(compiled) => compiled,
+            Err(e) => {
+                eprintln!("{} {}", color::paint("Compile Error:", RED), e);
+                continue;
+            }
+        };
 
-    let ts = lex(content);
-    if dbg {
-        println!("Tokens: {:?}", ts);
-    }
-    let mut lower_parser = LowerParser::new(ts);
-    let expr = lower_parser.parse();
-    if dbg {
-        println!("Low Parse: {:?}", expr);
+        let vm = vm.get_or_insert_with(|| {
+            let mut vm = VM::new(Closure::new(f.clone()), ffi);
+            vm.set_strict_numbers(opts.strict_numbers);
+            vm.set_memory_limit(opts.memory_limit);
+            vm
+        });
+        match vm.run_at(Closure::new(f), start, dbg) {
+            VMResult::Ok(Value::None) => (),
+            VMResult::Ok(x) => println!("{}", x.debug_repr()),
+            VMResult::Error(e) => eprintln!("{} {}", color::paint("Runtime Error:", RED), e),
+        }
     }
+}
 
-    let mut higher_parser = HigherParser::new(vec![expr], &ctx);
-    let core_expr = higher_parser.parse();
+// Lexes, parses and compiles files as one program, reporting any errors.
+fn compile(contents: &[String], ctx: &ParserContext, opts: &Options) -> Option<Function> {
+    let dbg = opts.dbg;
+    let sources: Vec<&str> = contents.iter().map(|c| c.as_str()).collect();
+    let core_expr = match bonsai::parse_all(&sources, ctx) {
+        Ok(core_expr) => core_expr,
+        Err(e) => {
+            eprintln!("{} {}", color::paint("Parse Error:", RED), e);
+            return None;
+        }
+    };
     if dbg {
         println!("High Parse: {:?}", core_expr);
     }
 
     let mut cc = Compiler::new(dbg);
-    cc.compile(&core_expr);
-    let f = cc.ctxs[0].function.clone();
-
-    let mut vm = VM::new(Closure::new(f), &ffi);
-    vm.run(dbg);
+    cc.set_max_depth(ctx.max_depth());
+    cc.set_inline_limit(opts.inline_limit);
+    match cc.compile_program(&core_expr) {
+        Ok(f) => Some(f),
+        Err(e) => {
+            eprintln!("{} {}", color::paint("Compile Error:", RED), e);
+            None
+        }
+    }
 }
 
-fn main() {
-    let mut ffi = FFI::new();
-    ffi.insert(
-        "print".to_string(),
-        Box::new(|x| {
-            println!("{}", x);
-            Value::Bool(false)
-        }),
-    );
-
-    ffi.insert(
-        "exit".to_string(),
-        Box::new(|_| {
-            println!("exiting");
-            std::process::exit(0);
-        }),
-    );
-
-    ffi.insert(
-        "time".to_string(),
-        Box::new(|_| {
-            let start = SystemTime::now();
-            let since_the_epoch = start
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards");
-            Value::Float((since_the_epoch.as_millis() as f64) * 0.001)
-        }),
-    );
-
-    let infix_ops = vec![
-        "".to_string(),
-        "/".to_string(),
-        "*".to_string(),
-        "-".to_string(),
-        "+".to_string(),
-        "==".to_string(),
-        "->".to_string(),
-        "=".to_string(),
-    ];
-
-    let mut prefix_macros = HashMap::new();
-    let mut infix_macros = HashMap::new();
-
-    // Prefix Macros
-    let prefix_return_macro: MacroRulePrefix =
-        Box::new(|ctx, expr| Core::Return(Box::new(HigherParser::new(expr.clone(), ctx).parse())));
-
-    let prefix_break_macro: MacroRulePrefix = Box::new(|_, _| Core::Break);
-
-    let prefix_continue_macro: MacroRulePrefix = Box::new(|_, _| Core::Continue);
-
-    let prefix_if_macro: MacroRulePrefix =
-        // If cond then on_true;
-        // If cond then on_true else on_true;
-        Box::new(|ctx, body| {
-            if body.len() == 3 || body.len() == 5 {
-                if let Expr::Name(n) = &body[1] {
-                    let cond = &body[0];
-                    assert_eq!(n, &"then".to_string());
-                    let on_true = &body[2];
-                    let mut on_false = &Expr::LitInt(0);
-                    if body.len() == 5 {
-                        if let Expr::Name(n) = &body[3] {
-                            assert_eq!(n, &"else".to_string());
-                            on_false = &body[4];
-                        }
-                    }
-                    return Core::If(
-                        Box::new(HigherParser::new(vec![cond.clone()], ctx).parse()),
-                        Box::new(HigherParser::new(vec![on_true.clone()], ctx).parse()),
-                        Box::new(HigherParser::new(vec![on_false.clone()], ctx).parse()),
-                    )
-                }
-            }
-            todo!()
-        });
+// Runs one or more files, in order, as a single program.
+fn run(
+    fnames: &[String],
+    contents: &[String],
+    ctx: &ParserContext,
+    ffi: &FFI,
+    opts: &Options,
+) -> Option<Value> {
+    let dbg = opts.dbg;
+    if !fnames.is_empty() {
+        println!("Running {}", fnames.join(", "));
+        println!("---");
+    }
 
-    let prefix_loop_macro: MacroRulePrefix =
-        Box::new(|ctx, body| Core::Loop(Box::new(HigherParser::new(body.clone(), ctx).parse())));
-
-    // Infix Macros
-    let infix_lambda_macro: MacroRuleInfix = Box::new(|op, ctx, args, body| {
-        Core::Lambda(
-            args.iter()
-                .map(|x| match x {
-                    Expr::Name(n) => n.clone(),
-                    _ => todo!(),
-                })
-                .collect(),
-            Box::new(Core::Block(vec![
-                HigherParser::new(body.clone(), ctx).parse()
-            ])),
-        )
-    });
-
-    let infix_assign_macro: MacroRuleInfix = Box::new(|op, ctx, vars, value| {
-        if vars.len() > 2 {
-            todo!()
-        } else if let Expr::Name(n) = vars.last().unwrap() {
-            let value = Box::new(HigherParser::new(value.clone(), ctx).parse());
-            if vars.len() == 1 {
-                Core::Set(n.clone(), value)
-            } else if let Expr::Name(l) = &vars[0] {
-                assert_eq!(l, "let");
-                Core::Let(n.clone(), value)
-            } else {
-                todo!()
-            }
-        } else {
-            todo!()
+    let f = compile(contents, ctx, opts)?;
+    let mut vm = VM::new(Closure::new(f), ffi);
+    vm.set_strict_numbers(opts.strict_numbers);
+    vm.set_memory_limit(opts.memory_limit);
+    let result = if opts.stats {
+        let (result, stats) = vm.run_with_stats(dbg);
+        eprintln!("{:?}", stats);
+        result
+    } else {
+        vm.run(dbg)
+    };
+    match result {
+        VMResult::Ok(x) => Some(x),
+        VMResult::Error(e) => {
+            eprintln!("{} {}", color::paint("Runtime Error:", RED), e);
+            None
         }
-    });
-
-    prefix_macros.insert("return".to_string(), prefix_return_macro);
-    prefix_macros.insert("continue".to_string(), prefix_continue_macro);
-    prefix_macros.insert("break".to_string(), prefix_break_macro);
-    prefix_macros.insert("if".to_string(), prefix_if_macro);
-    prefix_macros.insert("loop".to_string(), prefix_loop_macro);
-
-    infix_macros.insert("->".to_string(), infix_lambda_macro);
-    infix_macros.insert("=".to_string(), infix_assign_macro);
-
-    let ctx = ParserContext::new(&infix_ops, &infix_macros, &prefix_macros);
+    }
+}
 
-    let mut files = vec![];
-    let mut dbg = false;
+fn main() {
+    let mut ctx = ParserContext::default_bonsai();
+
+    // Flags come before the script, everything after it is the script's own.
+    // With --join every file up to a -- is part of the program.
+    let mut scripts = vec![];
+    let mut in_args = false;
+    let mut script_args = vec![];
+    let mut opts = Options {
+        dbg: false,
+        stats: false,
+        inline_limit: 0,
+        strict_numbers: false,
+        memory_limit: None,
+        join: false,
+        check: false,
+    };
+    let mut color_choice = ColorChoice::Auto;
     for x in env::args().skip(1) {
-        if x == "-d".to_string() || x == "--debug".to_string() {
-            dbg = true;
+        if in_args || (!opts.join && !opts.check && !scripts.is_empty()) {
+            script_args.push(x);
+        } else if opts.join && x == "--" {
+            in_args = true;
+        } else if x == "-c" || x == "--check" {
+            opts.check = true;
+        } else if x == "--join" {
+            opts.join = true;
+        } else if x == "-d" || x == "--debug" {
+            opts.dbg = true;
+        } else if x == "--no-color" {
+            color_choice = ColorChoice::Never;
+        } else if let Some(choice) = x.strip_prefix("--color=") {
+            color_choice = ColorChoice::parse(choice).expect("invalid --color value.");
+        } else if x == "--stats" {
+            opts.stats = true;
+        } else if x == "--strict-numbers" {
+            opts.strict_numbers = true;
+        } else if let Some(limit) = x.strip_prefix("--memory-limit=") {
+            opts.memory_limit = Some(limit.parse().expect("invalid --memory-limit value."));
+        } else if let Some(limit) = x.strip_prefix("--inline-limit=") {
+            opts.inline_limit = limit.parse().expect("invalid --inline-limit value.");
+        } else if let Some(depth) = x.strip_prefix("--max-depth=") {
+            ctx.set_max_depth(depth.parse().expect("invalid --max-depth value."));
         } else {
-            files.push((x.clone(), fs::read_to_string(x).expect("can't read file.")));
+            scripts.push(x);
         }
     }
 
-    if files.len() == 0 {
-        repl(&ctx, &ffi, dbg);
+    color::set(color_choice);
+
+    let mut ffi = FFI::new();
+    stdlib::install(&mut ffi);
+    stdlib::install_args(&mut ffi, script_args);
+
+    if scripts.is_empty() {
+        repl(&ctx, &ffi, &opts);
+    } else if opts.check {
+        // Nothing is run, each file (or the joined program) is only compiled.
+        let groups = if opts.join {
+            vec![scripts]
+        } else {
+            scripts.into_iter().map(|name| vec![name]).collect()
+        };
+        let mut failed = false;
+        for names in groups {
+            let contents: Vec<String> = names
+                .iter()
+                .map(|name| fs::read_to_string(name).expect("can't read file."))
+                .collect();
+            let ok = compile(&contents, &ctx, &opts).is_some();
+            let status = if ok {
+                color::paint("ok", GREEN)
+            } else {
+                color::paint("failed", RED)
+            };
+            println!("Checked {}: {}", names.join(", "), status);
+            failed |= !ok;
+        }
+        if failed {
+            std::process::exit(1);
+        }
     } else {
-        for (name, content) in files {
-            run(name, content, &ctx, &ffi, dbg)
+        let contents: Vec<String> = scripts
+            .iter()
+            .map(|name| fs::read_to_string(name).expect("can't read file."))
+            .collect();
+        if run(&scripts, &contents, &ctx, &ffi, &opts).is_none() {
+            std::process::exit(1);
         }
     }
 
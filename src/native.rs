@@ -1,29 +1,94 @@
 use std::collections::HashMap;
+use std::fmt;
 
 use crate::value::Value;
+use crate::vm::{ErrorKind, RuntimeError, VM};
 
-type NativeFn = Box<dyn Fn(&Value) -> Value>;
+// Natives get the running VM so that they can call back into bonsai code.
+pub type NativeFn = Box<dyn Fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>>;
 
+// How many arguments a native takes, FFI::call checks it so natives can
+// assume their arguments are there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    Range(usize, usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, nargs: usize) -> bool {
+        match *self {
+            Arity::Exact(n) => nargs == n,
+            Arity::Range(min, max) => (min..=max).contains(&nargs),
+            Arity::AtLeast(min) => nargs >= min,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arity::Exact(1) => write!(f, "1 argument"),
+            Arity::Exact(n) => write!(f, "{} arguments", n),
+            Arity::Range(min, max) => write!(f, "{} to {} arguments", min, max),
+            Arity::AtLeast(min) => write!(f, "at least {} arguments", min),
+        }
+    }
+}
+
+// The error every native gives for the wrong number of arguments, worded
+// like the one for closures.
+pub fn expect_args(name: &str, arity: Arity, args: &[Value]) -> Result<(), RuntimeError> {
+    if arity.accepts(args.len()) {
+        return Ok(());
+    }
+    Err(RuntimeError::of_kind(
+        ErrorKind::TypeError,
+        format!("{} expected {}, got {}", name, arity, args.len()),
+    ))
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct FFI {
-    map: HashMap<String, NativeFn>,
+    map: HashMap<String, (Arity, NativeFn)>,
+    // Native constants like pi, read by name without a call.
+    values: HashMap<String, Value>,
+}
+
+impl Default for FFI {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FFI {
     pub fn new() -> FFI {
         FFI {
             map: HashMap::new(),
+            values: HashMap::new(),
         }
     }
 
-    pub fn insert(&mut self, s: String, f: NativeFn) {
-        self.map.insert(s, f);
+    pub fn insert(&mut self, s: String, arity: Arity, f: NativeFn) {
+        self.map.insert(s, (arity, f));
+    }
+
+    pub fn insert_value(&mut self, s: String, value: Value) {
+        self.values.insert(s, value);
     }
 
-    pub fn call(&self, s: &String, arg: &Value) -> Value {
-        self.map.get(s).unwrap()(arg)
+    pub fn call(&self, s: &str, vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+        let (arity, f) = self.map.get(s).unwrap();
+        expect_args(s, *arity, args)?;
+        f(vm, args)
     }
 
-    pub fn has(&self, s: &String) -> bool {
+    pub fn has(&self, s: &str) -> bool {
         self.map.contains_key(s)
     }
+
+    pub fn value(&self, s: &str) -> Option<&Value> {
+        self.values.get(s)
+    }
 }
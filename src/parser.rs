@@ -1,9 +1,27 @@
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::fmt;
 
 use crate::common::Core;
-use crate::lexer::Tk;
+use crate::config::MAX_NESTING_DEPTH;
+use crate::lexer::{Span, Tk};
+use crate::macros;
 use crate::value::Value;
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError(pub String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+fn depth_error(max_depth: usize) -> ParseError {
+    ParseError(format!("Exceeded maximum nesting depth of {}", max_depth))
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, PartialEq, Clone)]
 pub enum Expr {
     FExpr(Vec<Expr>),
@@ -20,17 +38,28 @@ pub enum Expr {
 
 pub struct LowerParser {
     tokens: Vec<Tk>,
+    spans: Vec<Span>,
     current: usize,
+    depth: usize,
+    max_depth: usize,
 }
 
 impl LowerParser {
-    pub fn new(ts: Vec<Tk>) -> LowerParser {
+    pub fn new(ts: Vec<(Tk, Span)>) -> LowerParser {
+        let (tokens, spans) = ts.into_iter().unzip();
         LowerParser {
-            tokens: ts,
+            tokens,
+            spans,
             current: 0,
+            depth: 0,
+            max_depth: MAX_NESTING_DEPTH,
         }
     }
 
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
     fn advance(&mut self) -> Option<&Tk> {
         if self.tokens.len() >= self.current {
             self.current += 1;
@@ -40,18 +69,46 @@ impl LowerParser {
         }
     }
 
-    pub fn parse(&mut self) -> Expr {
+    // An error about the token just read, saying where it is.
+    fn error_at(&self, msg: String) -> ParseError {
+        let Span { line, col } = self.spans[self.current - 1];
+        ParseError(format!("{} at line {}, col {}", msg, line, col))
+    }
+
+    pub fn parse(&mut self) -> Result<Expr, ParseError> {
         self.list_expr(Tk::Semicolon, Tk::Eof, true)
     }
 
-    pub fn list_expr(&mut self, sep: Tk, end: Tk, newline_is_sep: bool) -> Expr {
+    pub fn list_expr(
+        &mut self,
+        sep: Tk,
+        end: Tk,
+        newline_is_sep: bool,
+    ) -> Result<Expr, ParseError> {
+        if self.depth >= self.max_depth {
+            return Err(depth_error(self.max_depth));
+        }
+        self.depth += 1;
+        let result = self.list_expr_inner(sep, end, newline_is_sep);
+        self.depth -= 1;
+        result
+    }
+
+    fn list_expr_inner(
+        &mut self,
+        sep: Tk,
+        end: Tk,
+        newline_is_sep: bool,
+    ) -> Result<Expr, ParseError> {
         let mut list: Vec<Expr> = vec![];
         let mut elem: Vec<Expr> = vec![];
+        let mut after_newline = false;
 
         while let Some(t) = self.advance() {
+            let is_newline = *t == Tk::NewLine;
             match t {
                 t if (*t == end) => {
-                    if elem.len() != 0 {
+                    if !elem.is_empty() {
                         if elem.len() != 1 {
                             list.push(Expr::FExpr(elem));
                         } else {
@@ -60,91 +117,169 @@ impl LowerParser {
                     };
 
                     if end == Tk::RParen && list.len() == 1 {
-                        return list[0].clone(); // (a b c) is grouping (a, b, c) is tuple
+                        if let Expr::NameInfix(_) = list[0] {
+                            // (+) is the operator itself rather than an infix use of it.
+                            return Ok(Expr::FExpr(list));
+                        }
+                        return Ok(list[0].clone()); // (a b c) is grouping (a, b, c) is tuple
                     } else {
-                        return match end {
+                        return Ok(match end {
                             Tk::RParen => Expr::Tuple(list),
                             Tk::RSquare => Expr::List(list),
                             Tk::RBrace | Tk::Eof => Expr::Block(list),
                             _ => panic!("Now what."),
-                        };
+                        });
                     }
                 }
 
+                // Blank lines and repeated separators don't make empty elements.
                 t if (*t == sep) || sep == Tk::Eof => {
                     if elem.len() == 1 {
                         list.push(elem[0].clone());
-                    } else {
+                    } else if !elem.is_empty() {
                         list.push(Expr::FExpr(elem));
                     }
                     elem = vec![];
                 }
 
+                // A line ending in an infix operator continues on the next one,
+                // `a +` then `b` is a + b. An operator after another one, as in
+                // `let add = +`, is a value and ends the line as usual.
+                Tk::NewLine
+                    if newline_is_sep
+                        && matches!(
+                            &elem[..],
+                            [.., x, Expr::NameInfix(_)] if !matches!(x, Expr::NameInfix(_))
+                        ) => {}
+
                 Tk::NewLine if newline_is_sep => {
                     if elem.len() == 1 {
                         list.push(elem[0].clone());
-                    } else {
+                    } else if !elem.is_empty() {
                         list.push(Expr::FExpr(elem));
                     }
                     elem = vec![];
                 }
 
-                Tk::LBrace => elem.push(self.list_expr(Tk::Semicolon, Tk::RBrace, true)),
+                Tk::LBrace => elem.push(self.list_expr(Tk::Semicolon, Tk::RBrace, true)?),
 
-                Tk::LParen => elem.push(self.list_expr(Tk::Comma, Tk::RParen, true)),
+                Tk::LParen => elem.push(self.list_expr(Tk::Comma, Tk::RParen, true)?),
 
-                Tk::LSquare => elem.push(self.list_expr(Tk::Comma, Tk::RSquare, false)),
+                Tk::LSquare => elem.push(self.list_expr(Tk::Comma, Tk::RSquare, false)?),
 
                 Tk::LitInt(n) => elem.push(Expr::LitInt(*n)),
                 Tk::LitFloat(n) => elem.push(Expr::LitFloat(*n)),
                 Tk::LitStr(s) => elem.push(Expr::LitStr(s.clone())),
+                // Until there are char values a character is a one letter string.
+                Tk::LitChar(c) => elem.push(Expr::LitStr(c.to_string())),
 
                 Tk::Name(n) => elem.push(Expr::Name(n.clone())),
-                Tk::NameInfix(n) => elem.push(Expr::NameInfix(n.clone())),
+                // Whereas `a` then `+ b` would silently be two statements.
+                Tk::NameInfix(n) | Tk::Operator(n)
+                    if newline_is_sep && elem.is_empty() && after_newline =>
+                {
+                    let msg = format!(
+                        "Line can't start with infix operator {}, end the previous line with it to continue",
+                        n
+                    );
+                    return Err(self.error_at(msg));
+                }
+                Tk::NameInfix(n) | Tk::Operator(n) => elem.push(Expr::NameInfix(n.clone())),
+                Tk::Colon => elem.push(Expr::NameInfix(":".to_string())),
 
-                _ => panic!("Unexpected Tk!{:?}", t),
+                Tk::Error(e) => {
+                    let msg = e.clone();
+                    return Err(self.error_at(msg));
+                }
+
+                _ => {
+                    let msg = format!("Unexpected token {:?}", t);
+                    return Err(self.error_at(msg));
+                }
             };
+            after_newline = is_newline;
         }
-        panic!("Unreachable")
+        Err(ParseError("Unexpected end of input".to_string()))
     }
 }
 
-pub struct ParserContext<'a> {
-    infix_operators: &'a Vec<String>,
-    infix_macros: &'a HashMap<String, MacroRuleInfix>,
-    prefix_macros: &'a HashMap<String, MacroRulePrefix>,
+pub struct ParserContext {
+    infix_operators: Vec<String>,
+    infix_macros: HashMap<String, MacroRuleInfix>,
+    prefix_macros: HashMap<String, MacroRulePrefix>,
+    depth: Cell<usize>,
+    max_depth: usize,
 }
 
-impl ParserContext<'_> {
-    pub fn new<'a>(
-        infix_operators: &'a Vec<String>,
-        infix_macros: &'a HashMap<String, MacroRuleInfix>,
-        prefix_macros: &'a HashMap<String, MacroRulePrefix>,
-    ) -> ParserContext<'a> {
+impl ParserContext {
+    pub fn new(
+        infix_operators: Vec<String>,
+        infix_macros: HashMap<String, MacroRuleInfix>,
+        prefix_macros: HashMap<String, MacroRulePrefix>,
+    ) -> ParserContext {
         ParserContext {
             infix_operators,
             infix_macros,
             prefix_macros,
+            depth: Cell::new(0),
+            max_depth: MAX_NESTING_DEPTH,
         }
     }
+
+    // The operators and macros that make up bonsai's own syntax.
+    pub fn default_bonsai() -> ParserContext {
+        ParserContext::new(
+            macros::infix_operators(),
+            macros::infix_macros(),
+            macros::prefix_macros(),
+        )
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    // Nested HigherParsers are created freely by macros, so the nesting depth
+    // is tracked here where all of them can see it.
+    fn enter(&self) -> Result<(), ParseError> {
+        if self.depth.get() >= self.max_depth {
+            return Err(depth_error(self.max_depth));
+        }
+        self.depth.set(self.depth.get() + 1);
+        Ok(())
+    }
+
+    fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+}
+
+// The operator "`" stands for every `name` used as an infix operator.
+fn infix_matches(op: &str, name: &str) -> bool {
+    op == name || (op == "`" && name.starts_with('`'))
 }
 
-pub type MacroRulePrefix = Box<dyn Fn(&ParserContext, &Vec<Expr>) -> Core>;
-pub type MacroRuleInfix = Box<dyn Fn(usize, &ParserContext, &Vec<Expr>, &Vec<Expr>) -> Core>;
+pub type MacroRulePrefix = Box<dyn Fn(&ParserContext, &Vec<Expr>) -> Result<Core, ParseError>>;
+pub type MacroRuleInfix =
+    Box<dyn Fn(usize, &ParserContext, &Vec<Expr>, &Vec<Expr>) -> Result<Core, ParseError>>;
 
 pub struct HigherParser<'a> {
     fexpr: Vec<Expr>,
     current_idx: usize,
-    ctx: &'a ParserContext<'a>,
+    ctx: &'a ParserContext,
 }
 
 // TODO: Unnecessarily Complex?
 impl HigherParser<'_> {
     pub fn new<'a>(fexpr: Vec<Expr>, ctx: &'a ParserContext) -> HigherParser<'a> {
         HigherParser {
-            fexpr: fexpr,
+            fexpr,
             current_idx: 0,
-            ctx: ctx,
+            ctx,
         }
     }
 
@@ -158,7 +293,7 @@ impl HigherParser<'_> {
 
     fn check_infix(&self, op_id: usize) -> bool {
         if let Some(Expr::NameInfix(y)) = self.peek() {
-            &self.ctx.infix_operators[op_id] == y
+            infix_matches(&self.ctx.infix_operators[op_id], y)
         } else {
             false
         }
@@ -167,7 +302,7 @@ impl HigherParser<'_> {
     fn check_infix_till_end(&self, op_id: usize) -> bool {
         for i in self.current_idx..self.fexpr.len() {
             if let Expr::NameInfix(y) = &self.fexpr[i] {
-                if &self.ctx.infix_operators[op_id] == y {
+                if infix_matches(&self.ctx.infix_operators[op_id], y) {
                     return true;
                 }
             }
@@ -175,14 +310,55 @@ impl HigherParser<'_> {
         false
     }
 
-    pub fn parse(&mut self) -> Core {
-        self.parse_infix(self.ctx.infix_operators.len() - 1)
+    pub fn parse(&mut self) -> Result<Core, ParseError> {
+        if let [Expr::NameInfix(op)] = &self.fexpr[..] {
+            return Ok(Core::Get(op.trim_matches('`').to_string()));
+        }
+        if let Some(section) = self.parse_section()? {
+            return Ok(section);
+        }
+        self.ctx.enter()?;
+        let result = self.parse_infix(self.ctx.infix_operators.len() - 1);
+        self.ctx.leave();
+        result
+    }
+
+    // (+ 1) is x -> x + 1 and (1 +) is x -> 1 + x. Not for macros, so
+    // `let f = a +` ending the input isn't taken for one.
+    fn parse_section(&self) -> Result<Option<Core>, ParseError> {
+        let is_macro = |x: &Expr| match x {
+            Expr::NameInfix(op) => self.ctx.infix_macros.contains_key(op),
+            Expr::Name(n) => self.ctx.prefix_macros.contains_key(n),
+            _ => false,
+        };
+        if self.fexpr.len() < 2 || self.fexpr.iter().any(is_macro) {
+            return Ok(None);
+        }
+
+        let param = " section".to_string();
+        let x = Core::Get(param.clone());
+        let (op, operands) = match &self.fexpr[..] {
+            [Expr::NameInfix(op), rest @ ..] => {
+                let y = HigherParser::new(rest.to_vec(), self.ctx).parse()?;
+                (op, vec![x, y])
+            }
+            [rest @ .., Expr::NameInfix(op)] => {
+                let y = HigherParser::new(rest.to_vec(), self.ctx).parse()?;
+                (op, vec![y, x])
+            }
+            _ => return Ok(None),
+        };
+        let op = Core::Get(op.trim_matches('`').to_string());
+        Ok(Some(Core::Lambda(
+            vec![param],
+            Box::new(Core::Call(Box::new(op), operands)),
+        )))
     }
 
     fn take_till_infix(&mut self, op_id: usize) -> Vec<Expr> {
         let mut xs = vec![];
         while !self.check_infix(op_id) {
-            if self.peek() == None {
+            if self.peek().is_none() {
                 return xs;
             }
             xs.push(self.peek().unwrap().clone());
@@ -191,7 +367,7 @@ impl HigherParser<'_> {
         xs
     }
 
-    fn parse_infix(&mut self, op_id: usize) -> Core {
+    fn parse_infix(&mut self, op_id: usize) -> Result<Core, ParseError> {
         if op_id == 0 {
             return self.parse_prefix();
         } else if self
@@ -203,7 +379,11 @@ impl HigherParser<'_> {
             let flat_left = self.take_till_infix(op_id);
             if self.check_infix(op_id) {
                 self.advance();
-                let flat_right = self.take_till_infix(op_id);
+                // Infix macros are right associative, the right side takes the
+                // rest: x = y = 5 is x = (y = 5), a -> b -> a + b is
+                // a -> (b -> a + b) and a ?? b ?? c is a ?? (b ?? c).
+                let flat_right = self.fexpr[self.current_idx..].to_vec();
+                self.current_idx = self.fexpr.len();
                 return self
                     .ctx
                     .infix_macros
@@ -214,65 +394,108 @@ impl HigherParser<'_> {
             }
         }
 
-        let mut left = self.parse_infix(op_id - 1);
+        let left = self.parse_infix(op_id - 1)?;
+        // Each operator nests the left side a level deeper, so a long flat
+        // chain counts against the depth limit like nested parentheses do.
+        let mut links = 0;
+        let result = self.parse_links(op_id, left, &mut links);
+        for _ in 0..links {
+            self.ctx.leave();
+        }
+        result
+    }
+
+    fn parse_links(
+        &mut self,
+        op_id: usize,
+        mut left: Core,
+        links: &mut usize,
+    ) -> Result<Core, ParseError> {
         while self.check_infix(op_id) {
+            let op = match self.peek() {
+                Some(Expr::NameInfix(op)) => op.trim_matches('`').to_string(),
+                _ => unreachable!(),
+            };
             self.advance();
+            self.ctx.enter()?;
+            *links += 1;
 
-            let right = self.parse_infix(op_id - 1);
+            let right = self.parse_infix(op_id - 1)?;
 
-            left = Core::Call(
-                Box::new(Core::Get(self.ctx.infix_operators[op_id].clone())),
-                vec![left, right],
-            );
+            left = Core::Call(Box::new(Core::Get(op)), vec![left, right]);
         }
-        left
+        Ok(left)
     }
 
-    pub fn parse_prefix(&mut self) -> Core {
+    pub fn parse_prefix(&mut self) -> Result<Core, ParseError> {
         if let Some(Expr::Name(x)) = self.peek() {
             if self.ctx.prefix_macros.contains_key(x) {
                 return self.ctx.prefix_macros.get(x).unwrap()(
                     self.ctx,
-                    &self.fexpr.iter().skip(1).map(|x| x.clone()).collect(),
+                    &self.fexpr.iter().skip(1).cloned().collect(),
                 );
             }
         }
 
         let mut fcall = vec![];
+        let mut nullary_call = false;
         while let Some(x) = self.peek() {
             let x = x.clone();
             let arg = match x {
+                // `f ()` calls f without any arguments.
+                Expr::Tuple(xs) if xs.is_empty() && !fcall.is_empty() => {
+                    self.advance();
+                    nullary_call = true;
+                    continue;
+                }
+                Expr::Tuple(xs) if xs.is_empty() => Core::Lit(Value::None),
+
                 Expr::LitStr(s) => Core::Lit(Value::Str(s.clone())),
                 Expr::LitFloat(f) => Core::Lit(Value::Float(f)),
                 Expr::LitInt(i) => Core::Lit(Value::Int(i)),
 
-                Expr::FExpr(xs) => HigherParser::new(xs, self.ctx).parse(),
+                Expr::FExpr(xs) => HigherParser::new(xs, self.ctx).parse()?,
 
                 Expr::Block(xs) => {
                     let mut block = vec![];
                     for x in xs {
-                        block.push(HigherParser::new(vec![x], self.ctx).parse());
+                        block.push(HigherParser::new(vec![x], self.ctx).parse()?);
                     }
                     Core::Block(block)
                 }
 
+                Expr::List(xs) => {
+                    let mut list = vec![];
+                    for x in xs {
+                        list.push(HigherParser::new(vec![x], self.ctx).parse()?);
+                    }
+                    Core::List(list)
+                }
+
+                Expr::Name(n) if n == "none" => Core::Lit(Value::None),
+                Expr::Name(n) if n == "true" => Core::Lit(Value::Bool(true)),
+                Expr::Name(n) if n == "false" => Core::Lit(Value::Bool(false)),
                 Expr::Name(n) => Core::Get(n.clone()),
 
                 Expr::NameInfix(_) => break,
 
-                _ => todo!(),
+                Expr::Tuple(_) => {
+                    return Err(ParseError(
+                        "Tuples aren't values, use a list [a, b] instead".to_string(),
+                    ))
+                }
             };
             self.advance();
             fcall.push(arg);
         }
 
-        if fcall.len() == 1 {
-            fcall[0].clone()
+        if fcall.len() == 1 && !nullary_call {
+            Ok(fcall[0].clone())
         } else {
-            Core::Call(
+            Ok(Core::Call(
                 Box::new(fcall[0].clone()),
-                fcall.iter().skip(1).map(|x| x.clone()).collect(),
-            )
+                fcall.iter().skip(1).cloned().collect(),
+            ))
         }
     }
 }
@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::value::{Closure, Collection, HeapedData, Iter, List, ListIter, Map, Value};
+
+// Copies values so that the copies share nothing with the originals. Whatever
+// the originals shared among themselves, including a list inside itself, is
+// shared the same way among the copies made by one DeepCopy.
+#[derive(Default)]
+pub struct DeepCopy {
+    lists: HashMap<*const (), List>,
+    maps: HashMap<*const (), Map>,
+    iters: HashMap<*const (), Iter>,
+    upvalues: HashMap<*const (), Rc<RefCell<Vec<HeapedData>>>>,
+    cells: HashMap<*const (), HeapedData>,
+}
+
+impl DeepCopy {
+    pub fn value(&mut self, x: &Value) -> Value {
+        match x {
+            Value::List(xs) => Value::List(self.list(xs)),
+            Value::Map(m) => Value::Map(self.map(m)),
+            Value::Iterator(it) => Value::Iterator(self.iter(it)),
+            Value::Closure(c) => Value::Closure(self.closure(c)),
+            x => x.clone(),
+        }
+    }
+
+    pub fn closure(&mut self, c: &Closure) -> Closure {
+        let id = Rc::as_ptr(&c.upvalues) as *const ();
+        let upvalues = match self.upvalues.get(&id) {
+            Some(copy) => Rc::clone(copy),
+            None => {
+                let copy = Rc::new(RefCell::new(vec![]));
+                self.upvalues.insert(id, Rc::clone(&copy));
+                let cells = c.upvalues.borrow().iter().map(|x| self.cell(x)).collect();
+                *copy.borrow_mut() = cells;
+                copy
+            }
+        };
+        Closure {
+            function: c.function.clone(),
+            upvalues,
+        }
+    }
+
+    fn cell(&mut self, x: &HeapedData) -> HeapedData {
+        let id = Rc::as_ptr(x) as *const ();
+        if let Some(copy) = self.cells.get(&id) {
+            return Rc::clone(copy);
+        }
+        let copy = Rc::new(RefCell::new(Value::None));
+        self.cells.insert(id, Rc::clone(&copy));
+        let value = self.value(&x.borrow());
+        *copy.borrow_mut() = value;
+        copy
+    }
+
+    // Each copy is registered before its items are copied, so an item which
+    // leads back to it finds the copy instead of starting another.
+    fn list(&mut self, xs: &List) -> List {
+        let id = Rc::as_ptr(xs) as *const ();
+        if let Some(copy) = self.lists.get(&id) {
+            return Rc::clone(copy);
+        }
+        let copy = Collection::new(vec![]);
+        self.lists.insert(id, Rc::clone(&copy));
+        let items = xs.borrow().iter().map(|x| self.value(x)).collect();
+        *copy.borrow_mut().unwrap() = items;
+        if xs.is_frozen() {
+            copy.freeze();
+        }
+        copy
+    }
+
+    fn map(&mut self, m: &Map) -> Map {
+        let id = Rc::as_ptr(m) as *const ();
+        if let Some(copy) = self.maps.get(&id) {
+            return Rc::clone(copy);
+        }
+        let copy = Collection::new(Default::default());
+        self.maps.insert(id, Rc::clone(&copy));
+        let items = m
+            .borrow()
+            .iter()
+            .map(|(k, v)| (k.clone(), self.value(v)))
+            .collect();
+        *copy.borrow_mut().unwrap() = items;
+        if m.is_frozen() {
+            copy.freeze();
+        }
+        copy
+    }
+
+    fn iter(&mut self, it: &Iter) -> Iter {
+        let id = Rc::as_ptr(it) as *const ();
+        if let Some(copy) = self.iters.get(&id) {
+            return Rc::clone(copy);
+        }
+        let it = it.borrow();
+        let copy = ListIter::new(self.list(&it.items));
+        copy.borrow_mut().next = it.next;
+        self.iters.insert(id, Rc::clone(&copy));
+        copy
+    }
+}
+
+impl Value {
+    // A copy sharing nothing with the original, as DeepCopy makes. Every
+    // value bonsai has can be copied, the error is for host values which
+    // can't be.
+    pub fn try_clone_deep(&self) -> Result<Value, String> {
+        Ok(DeepCopy::default().value(self))
+    }
+}
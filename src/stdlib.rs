@@ -0,0 +1,751 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, Read};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::native::{Arity, FFI};
+use crate::value::{Collection, Value};
+use crate::vm::{self, ErrorKind, RuntimeError};
+
+type BinaryOp = fn(Value, Value) -> Result<Value, RuntimeError>;
+
+type Comparator<'a> = dyn FnMut(&Value, &Value) -> Result<Ordering, RuntimeError> + 'a;
+
+// Stable merge sort which gives up on the first failed comparison, the
+// comparator may be arbitrary bonsai code so it can't go through slice::sort_by.
+fn merge_sort(xs: Vec<Value>, cmp: &mut Comparator) -> Result<Vec<Value>, RuntimeError> {
+    if xs.len() <= 1 {
+        return Ok(xs);
+    }
+    let mut left = xs;
+    let right = left.split_off(left.len() / 2);
+    let left = merge_sort(left, cmp)?;
+    let right = merge_sort(right, cmp)?;
+
+    let mut sorted = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(x), Some(y)) = (left.peek(), right.peek()) {
+        if cmp(x, y)? == Ordering::Greater {
+            sorted.push(right.next().unwrap());
+        } else {
+            sorted.push(left.next().unwrap());
+        }
+    }
+    sorted.extend(left);
+    sorted.extend(right);
+    Ok(sorted)
+}
+
+// Shared by min and max, `keep` is the ordering of a new best value against the
+// current one. Ties keep the earlier argument.
+fn extremum(args: &[Value], keep: Ordering) -> Result<Value, RuntimeError> {
+    let mut best = &args[0];
+    best.expect_float()?;
+    for x in &args[1..] {
+        if x.expect_float()?.partial_cmp(&best.expect_float()?) == Some(keep) {
+            best = x;
+        }
+    }
+    Ok(best.clone())
+}
+
+// C's %a: the mantissa in hex and a binary exponent, exact for every float.
+fn hex_float(x: f64) -> String {
+    if !x.is_finite() {
+        return x.to_string();
+    }
+    let sign = if x.is_sign_negative() { "-" } else { "" };
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i64;
+    let mantissa = bits & ((1 << 52) - 1);
+    let (lead, exponent) = match (exponent, mantissa) {
+        (0, 0) => (0, 0),
+        (0, _) => (0, -1022),
+        (e, _) => (1, e - 1023),
+    };
+    let digits = format!("{:013x}", mantissa);
+    let digits = digits.trim_end_matches('0');
+    let dot = if digits.is_empty() { "" } else { "." };
+    format!("{}0x{}{}{}p{:+}", sign, lead, dot, digits, exponent)
+}
+
+// Specs for fmt_float: "hex", "e" or "e:N" for scientific, "fixed:N".
+fn format_float_spec(x: f64, spec: &str) -> Option<String> {
+    let (kind, precision) = match spec.split_once(':') {
+        Some((kind, n)) => (kind, Some(n.parse::<usize>().ok()?)),
+        None => (spec, None),
+    };
+    Some(match (kind, precision) {
+        ("hex", None) => hex_float(x),
+        ("e", None) => format!("{:e}", x),
+        ("e", Some(n)) => format!("{:.*e}", n, x),
+        ("fixed", Some(n)) => format!("{:.*}", n, x),
+        _ => return None,
+    })
+}
+
+// Whether an endianness argument, "little" or "big", is little endian.
+fn is_little_endian(x: &Value) -> Result<bool, RuntimeError> {
+    match x.expect_str()? {
+        "little" => Ok(true),
+        "big" => Ok(false),
+        x => Err(RuntimeError::new(format!(
+            "unknown endianness {:?}, expected \"little\" or \"big\"",
+            x
+        ))),
+    }
+}
+
+// `args` is the list of command line arguments given to the script.
+pub fn install_args(ffi: &mut FFI, args: Vec<String>) {
+    let args = args.into_iter().map(Value::Str).collect();
+    ffi.insert_value("args".to_string(), Value::List(Collection::new(args)));
+}
+
+pub fn install(ffi: &mut FFI) {
+    ffi.insert_value("pi".to_string(), Value::Float(std::f64::consts::PI));
+
+    ffi.insert(
+        "print".to_string(),
+        Arity::AtLeast(0),
+        Box::new(|_, args| {
+            let line: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+            println!("{}", line.join(" "));
+            Ok(Value::None)
+        }),
+    );
+
+    ffi.insert(
+        "exit".to_string(),
+        Arity::Exact(0),
+        Box::new(|_, _| {
+            println!("exiting");
+            std::process::exit(0);
+        }),
+    );
+
+    // Raises a runtime error with the given message, which a try can catch.
+    // Uncaught it ends the script like any other runtime error.
+    ffi.insert(
+        "panic".to_string(),
+        Arity::AtLeast(0),
+        Box::new(|_, args| {
+            let msg: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+            Err(RuntimeError::new(msg.join(" ")))
+        }),
+    );
+
+    ffi.insert(
+        "time".to_string(),
+        Arity::Exact(0),
+        Box::new(|_, _| {
+            let start = SystemTime::now();
+            let since_the_epoch = start
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards");
+            Ok(Value::Float((since_the_epoch.as_millis() as f64) * 0.001))
+        }),
+    );
+
+    ffi.insert(
+        "sleep".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| {
+            let seconds = args[0].expect_float()?;
+            if seconds < 0.0 {
+                return Err(RuntimeError::new("sleep duration can't be negative"));
+            }
+            let duration = Duration::try_from_secs_f64(seconds)
+                .map_err(|_| RuntimeError::new(format!("can't sleep for {} seconds", seconds)))?;
+            thread::sleep(duration);
+            Ok(Value::None)
+        }),
+    );
+
+    // Operators, the compiler emits opcodes for direct calls so these are only
+    // reached when an operator is used as a value, e.g. `sort_by xs (-)`.
+    let operators: [(&str, BinaryOp); 10] = [
+        ("+", vm::add),
+        ("-", vm::subtract),
+        ("*", vm::multiply),
+        ("/", vm::divide),
+        ("%", vm::modulo),
+        ("==", |x, y| Ok(Value::Bool(vm::is_equal(&x, &y)))),
+        ("<", vm::less),
+        (">", vm::greater),
+        ("<=", vm::less_equal),
+        (">=", vm::greater_equal),
+    ];
+    for (name, f) in operators {
+        let verb = match name {
+            "+" => Some("add"),
+            "-" => Some("subtract"),
+            "*" => Some("multiply"),
+            "/" => Some("divide"),
+            "%" => Some("take the remainder of"),
+            _ => None,
+        };
+        ffi.insert(
+            name.to_string(),
+            Arity::Exact(2),
+            Box::new(move |vm, args| {
+                if let Some(verb) = verb {
+                    vm.check_promotion(verb, &args[0], &args[1])?;
+                }
+                f(args[0].clone(), args[1].clone())
+            }),
+        );
+    }
+
+    // Numbers
+    ffi.insert(
+        "min".to_string(),
+        Arity::AtLeast(2),
+        Box::new(|_, args| extremum(args, Ordering::Less)),
+    );
+
+    ffi.insert(
+        "max".to_string(),
+        Arity::AtLeast(2),
+        Box::new(|_, args| extremum(args, Ordering::Greater)),
+    );
+
+    // Debugging
+    ffi.insert(
+        "stacktrace".to_string(),
+        Arity::Exact(0),
+        Box::new(|vm, _| {
+            let frames = vm.stacktrace().into_iter().map(Value::Str).collect();
+            Ok(Value::List(Collection::new(frames)))
+        }),
+    );
+
+    // Bools don't take part in arithmetic, counting has to convert explicitly.
+    ffi.insert(
+        "bool_to_int".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [x] => Ok(Value::Int(x.expect_bool()? as isize)),
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "globals".to_string(),
+        Arity::Exact(0),
+        Box::new(|vm, _| {
+            let names = vm.global_names().into_iter().map(Value::Str).collect();
+            Ok(Value::List(Collection::new(names)))
+        }),
+    );
+
+    // Input
+    ffi.insert(
+        "read_all".to_string(),
+        Arity::Exact(0),
+        Box::new(|_, _| {
+            let mut s = String::new();
+            match io::stdin().read_to_string(&mut s) {
+                Ok(_) => Ok(Value::Str(s)),
+                Err(e) => Err(RuntimeError::new(format!("read_all failed: {}", e))),
+            }
+        }),
+    );
+
+    // None when the variable isn't set or isn't valid unicode.
+    ffi.insert(
+        "getenv".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| {
+            let name = args[0].expect_str()?;
+            Ok(env::var(name).map_or(Value::None, Value::Str))
+        }),
+    );
+
+    // Values
+    ffi.insert(
+        "type".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [x] => Ok(Value::Str(x.type_name().to_string())),
+            _ => unreachable!(),
+        }),
+    );
+
+    // Strings
+    ffi.insert(
+        "split".to_string(),
+        Arity::Exact(2),
+        Box::new(|_, args| match args {
+            [s, sep] => {
+                let (s, sep) = (s.expect_str()?, sep.expect_str()?);
+                if sep.is_empty() {
+                    return Err(RuntimeError::new("split separator can't be empty"));
+                }
+                let parts = s.split(sep).map(|x| Value::Str(x.to_string()));
+                Ok(Value::List(Collection::new(parts.collect())))
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "repeat_str".to_string(),
+        Arity::Exact(2),
+        Box::new(|_, args| match args {
+            [s, n] => {
+                let (s, n) = (s.expect_str()?, n.expect_int()?);
+                if n < 0 {
+                    return Err(RuntimeError::new("repeat_str count can't be negative"));
+                }
+                Ok(Value::Str(s.repeat(n as usize)))
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    // The inverse of split, every item must be a string.
+    ffi.insert(
+        "join".to_string(),
+        Arity::Exact(2),
+        Box::new(|_, args| match args {
+            [xs, sep] => {
+                let sep = sep.expect_str()?;
+                let parts = xs
+                    .expect_list()?
+                    .borrow()
+                    .iter()
+                    .map(|x| x.expect_str().map(str::to_string))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Value::Str(parts.join(sep)))
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    // Lists
+    ffi.insert(
+        "len".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [Value::Str(s)] => Ok(Value::Int(s.chars().count() as isize)),
+            [Value::Map(m)] => Ok(Value::Int(m.borrow().len() as isize)),
+            [xs] => Ok(Value::Int(xs.expect_list()?.borrow().len() as isize)),
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "push".to_string(),
+        Arity::Exact(2),
+        Box::new(|_, args| match args {
+            [xs, x] => {
+                xs.expect_list()?.borrow_mut()?.push(x.clone());
+                Ok(Value::None)
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "nth".to_string(),
+        Arity::Exact(2),
+        Box::new(|_, args| match args {
+            [xs, i] => {
+                let (xs, i) = (xs.expect_list()?.borrow(), i.expect_int()?);
+                let x = usize::try_from(i).ok().and_then(|i| xs.get(i));
+                x.cloned().ok_or_else(|| {
+                    RuntimeError::of_kind(
+                        ErrorKind::KeyError,
+                        format!("index {} out of range for list of length {}", i, xs.len()),
+                    )
+                })
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    // The same comparison as ==, as a plain function for passing around.
+    ffi.insert(
+        "equal".to_string(),
+        Arity::Exact(2),
+        Box::new(|_, args| match args {
+            [x, y] => Ok(Value::Bool(vm::is_equal(x, y))),
+            _ => unreachable!(),
+        }),
+    );
+
+    // Unlike assignment, which shares lists and maps, a clone can be changed
+    // without touching the original.
+    ffi.insert(
+        "clone".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [x] => x
+                .try_clone_deep()
+                .map_err(|e| RuntimeError::of_kind(ErrorKind::TypeError, e)),
+            _ => unreachable!(),
+        }),
+    );
+
+    // Freezing is permanent and seen through every copy of the collection.
+    ffi.insert(
+        "freeze".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [x] => {
+                match x {
+                    Value::List(xs) => xs.freeze(),
+                    Value::Map(m) => m.freeze(),
+                    x => {
+                        return Err(RuntimeError::of_kind(
+                            ErrorKind::TypeError,
+                            format!("can't freeze {}", x.type_name()),
+                        ))
+                    }
+                }
+                Ok(x.clone())
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "range".to_string(),
+        Arity::Range(1, 3),
+        Box::new(|_, args| {
+            let (start, stop, step) = match args {
+                [stop] => (0, stop.expect_int()?, 1),
+                [start, stop] => (start.expect_int()?, stop.expect_int()?, 1),
+                [start, stop, step] => {
+                    (start.expect_int()?, stop.expect_int()?, step.expect_int()?)
+                }
+                _ => unreachable!(),
+            };
+            if step == 0 {
+                return Err(RuntimeError::new("range step can't be zero"));
+            }
+
+            let mut xs = vec![];
+            let mut i = start;
+            while (step > 0 && i < stop) || (step < 0 && i > stop) {
+                xs.push(Value::Int(i));
+                match i.checked_add(step) {
+                    Some(next) => i = next,
+                    None => break,
+                }
+            }
+            Ok(Value::List(Collection::new(xs)))
+        }),
+    );
+
+    // Pairs each item with its index, as two item lists since there are no
+    // tuple values.
+    ffi.insert(
+        "enumerate".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [xs] => {
+                let pairs = xs
+                    .expect_list()?
+                    .borrow()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| {
+                        Value::List(Collection::new(vec![Value::Int(i as isize), x.clone()]))
+                    })
+                    .collect();
+                Ok(Value::List(Collection::new(pairs)))
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "foreach".to_string(),
+        Arity::Exact(2),
+        Box::new(|vm, args| match args {
+            [xs, f] => {
+                // Iterate over a snapshot so f can push to the list it is given.
+                let xs = xs.expect_list()?.borrow().clone();
+                for x in xs {
+                    vm.call_value(f, &[x])?;
+                }
+                Ok(Value::None)
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "sort".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [xs] => {
+                let sorted = merge_sort(xs.expect_list()?.borrow().clone(), &mut |x, y| {
+                    x.compare(y).ok_or_else(|| {
+                        RuntimeError::new(format!(
+                            "sort can't compare {} and {}",
+                            x.type_name(),
+                            y.type_name()
+                        ))
+                    })
+                })?;
+                Ok(Value::List(Collection::new(sorted)))
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "sort_by".to_string(),
+        Arity::Exact(2),
+        Box::new(|vm, args| match args {
+            [xs, f] => {
+                let xs = xs.expect_list()?.borrow().clone();
+                let sorted = merge_sort(xs, &mut |x, y| {
+                    let n = vm.call_value(f, &[x.clone(), y.clone()])?.expect_float()?;
+                    Ok(n.partial_cmp(&0.0).unwrap_or(Ordering::Equal))
+                })?;
+                Ok(Value::List(Collection::new(sorted)))
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    // Maps, keys are strings and kept in sorted order.
+    ffi.insert(
+        "map_new".to_string(),
+        Arity::Exact(0),
+        Box::new(|_, args| match args {
+            [] => Ok(Value::Map(Collection::new(BTreeMap::new()))),
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "map_set".to_string(),
+        Arity::Exact(3),
+        Box::new(|_, args| match args {
+            [m, k, v] => {
+                let k = k.expect_str()?.to_string();
+                m.expect_map()?.borrow_mut()?.insert(k, v.clone());
+                Ok(Value::None)
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "map_get".to_string(),
+        Arity::Exact(2),
+        Box::new(|_, args| match args {
+            [m, k] => {
+                let k = k.expect_str()?;
+                let v = m.expect_map()?.borrow().get(k).cloned();
+                v.ok_or_else(|| {
+                    RuntimeError::of_kind(ErrorKind::KeyError, format!("key {:?} not found", k))
+                })
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "has_key".to_string(),
+        Arity::Exact(2),
+        Box::new(|_, args| match args {
+            [m, k] => {
+                let k = k.expect_str()?;
+                Ok(Value::Bool(m.expect_map()?.borrow().contains_key(k)))
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    // Keys come out in sorted order, values in the order of their keys.
+    ffi.insert(
+        "keys".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [m] => {
+                let keys = m
+                    .expect_map()?
+                    .borrow()
+                    .keys()
+                    .cloned()
+                    .map(Value::Str)
+                    .collect();
+                Ok(Value::List(Collection::new(keys)))
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "values".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [m] => {
+                let values = m.expect_map()?.borrow().values().cloned().collect();
+                Ok(Value::List(Collection::new(values)))
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    // Conversions, str of a float round-trips through float exactly.
+    ffi.insert(
+        "str".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [x] => Ok(Value::Str(x.to_string())),
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "float".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [Value::Str(s)] => s
+                .trim()
+                .parse()
+                .map(Value::Float)
+                .map_err(|_| RuntimeError::new(format!("can't convert {:?} to float", s))),
+            [x] => Ok(Value::Float(x.expect_float()?)),
+            _ => unreachable!(),
+        }),
+    );
+
+    // Floats are truncated toward zero.
+    ffi.insert(
+        "int".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [Value::Str(s)] => s
+                .trim()
+                .parse()
+                .map(Value::Int)
+                .map_err(|_| RuntimeError::new(format!("can't convert {:?} to int", s))),
+            [Value::Float(x)] => {
+                let truncated = x.trunc();
+                // isize::MAX as f64 rounds up to 2^63, which is already too big.
+                if truncated >= -(isize::MAX as f64) - 1.0 && truncated < isize::MAX as f64 {
+                    Ok(Value::Int(truncated as isize))
+                } else {
+                    Err(RuntimeError::new(format!(
+                        "can't convert {} to int",
+                        Value::Float(*x)
+                    )))
+                }
+            }
+            [x] => Ok(Value::Int(x.expect_int()?)),
+            _ => unreachable!(),
+        }),
+    );
+
+    // Formatting
+    ffi.insert(
+        "hex".to_string(),
+        Arity::Exact(1),
+        Box::new(|_, args| match args {
+            [x] => {
+                let x = x.expect_int()?;
+                let sign = if x < 0 { "-" } else { "" };
+                Ok(Value::Str(format!("{}{:#x}", sign, x.unsigned_abs())))
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "format_float".to_string(),
+        Arity::Exact(2),
+        Box::new(|_, args| match args {
+            [x, precision] => {
+                let (x, precision) = (x.expect_float()?, precision.expect_int()?);
+                if precision < 0 {
+                    return Err(RuntimeError::new(
+                        "format_float precision can't be negative",
+                    ));
+                }
+                Ok(Value::Str(format!("{:.*}", precision as usize, x)))
+            }
+            _ => unreachable!(),
+        }),
+    );
+
+    ffi.insert(
+        "fmt_float".to_string(),
+        Arity::Exact(2),
+        Box::new(|_, args| {
+            let (x, spec) = (args[0].expect_float()?, args[1].expect_str()?);
+            format_float_spec(x, spec)
+                .map(Value::Str)
+                .ok_or_else(|| RuntimeError::new(format!("unknown float format {:?}", spec)))
+        }),
+    );
+
+    // Binary formats, bytes are lists of ints from 0 to 255. Both take the
+    // int as unsigned and 1 to 8 bytes wide.
+    ffi.insert(
+        "int_to_bytes".to_string(),
+        Arity::Exact(3),
+        Box::new(|_, args| {
+            let (n, width) = (args[0].expect_int()?, args[1].expect_int()?);
+            let little = is_little_endian(&args[2])?;
+            if !(1..=8).contains(&width) {
+                return Err(RuntimeError::new(format!(
+                    "int_to_bytes width must be 1 to 8, got {}",
+                    width
+                )));
+            }
+            let width = width as usize;
+            if n < 0 || (width < 8 && n >> (8 * width) != 0) {
+                return Err(RuntimeError::new(format!(
+                    "{} is out of range for {}-byte unsigned ints",
+                    n, width
+                )));
+            }
+            let mut bytes = (n as u64).to_le_bytes()[..width].to_vec();
+            if !little {
+                bytes.reverse();
+            }
+            let bytes = bytes.into_iter().map(|b| Value::Int(b as isize)).collect();
+            Ok(Value::List(Collection::new(bytes)))
+        }),
+    );
+
+    ffi.insert(
+        "bytes_to_int".to_string(),
+        Arity::Exact(2),
+        Box::new(|_, args| {
+            let little = is_little_endian(&args[1])?;
+            let mut bytes = vec![];
+            for b in args[0].expect_list()?.borrow().iter() {
+                match b.expect_int()? {
+                    b @ 0..=255 => bytes.push(b as u8),
+                    b => return Err(RuntimeError::new(format!("{} is not a byte", b))),
+                }
+            }
+            if !(1..=8).contains(&bytes.len()) {
+                return Err(RuntimeError::new(format!(
+                    "bytes_to_int takes 1 to 8 bytes, got {}",
+                    bytes.len()
+                )));
+            }
+            if !little {
+                bytes.reverse();
+            }
+            let mut le = [0; 8];
+            le[..bytes.len()].copy_from_slice(&bytes);
+            isize::try_from(u64::from_le_bytes(le))
+                .map(Value::Int)
+                .map_err(|_| RuntimeError::new("bytes_to_int result doesn't fit in an int"))
+        }),
+    );
+}
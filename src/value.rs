@@ -1,22 +1,166 @@
 use crate::common::Chunk;
-use std::cell::RefCell;
+use crate::config::MAX_PRINT_DEPTH;
+use crate::vm::{ErrorKind, RuntimeError};
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub struct Function {
     pub arity: usize,
     pub upvalue_count: usize,
+    pub max_stack: usize, // Most values the function has on the stack at once, arguments included
+    pub name: Option<String>, // The name a let bound the lambda to, None for anonymous ones
     pub chunk: Chunk,
 }
 
 pub type HeapedData = Rc<RefCell<Value>>;
+pub type List = Rc<Collection<Vec<Value>>>;
+pub type Map = Rc<Collection<BTreeMap<String, Value>>>;
+
+thread_local! {
+    // Bytes held by the lists and maps alive on this thread, see Slots.
+    static COLLECTION_BYTES: Cell<usize> = const { Cell::new(0) };
+}
+
+pub fn collection_bytes() -> usize {
+    COLLECTION_BYTES.with(Cell::get)
+}
+
+fn adjust_collection_bytes(before: usize, after: usize) {
+    COLLECTION_BYTES.with(|x| x.set(x.get() + after - before));
+}
+
+// What a collection counts towards collection_bytes: its slots, not what
+// they point to. Nested collections count themselves, strings aren't counted.
+pub trait Slots {
+    const SLOT_BYTES: usize;
+    fn slots(&self) -> usize;
+}
+
+impl Slots for Vec<Value> {
+    const SLOT_BYTES: usize = mem::size_of::<Value>();
+    fn slots(&self) -> usize {
+        self.len()
+    }
+}
+
+impl Slots for BTreeMap<String, Value> {
+    const SLOT_BYTES: usize = mem::size_of::<String>() + mem::size_of::<Value>();
+    fn slots(&self) -> usize {
+        self.len()
+    }
+}
+
+// The items of a list or map, shared by every copy of the value. A frozen
+// collection can still be read but refuses mutable access.
+#[derive(Debug)]
+pub struct Collection<T> {
+    items: RefCell<T>,
+    frozen: Cell<bool>,
+    bytes: Cell<usize>, // Counted towards collection_bytes
+}
+
+impl<T: Slots> Collection<T> {
+    pub fn new(items: T) -> Rc<Collection<T>> {
+        let bytes = items.slots() * T::SLOT_BYTES;
+        adjust_collection_bytes(0, bytes);
+        Rc::new(Collection {
+            items: RefCell::new(items),
+            frozen: Cell::new(false),
+            bytes: Cell::new(bytes),
+        })
+    }
+
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.items.borrow()
+    }
+
+    pub fn borrow_mut(&self) -> Result<CollectionMut<'_, T>, RuntimeError> {
+        if self.frozen.get() {
+            return Err(RuntimeError::new("cannot mutate frozen value"));
+        }
+        Ok(CollectionMut {
+            items: self.items.borrow_mut(),
+            bytes: &self.bytes,
+        })
+    }
+
+    pub fn freeze(&self) {
+        self.frozen.set(true);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.get()
+    }
+}
+
+impl<T> Drop for Collection<T> {
+    fn drop(&mut self) {
+        adjust_collection_bytes(self.bytes.get(), 0);
+    }
+}
+
+// Mutable access to a collection's items, recounting its bytes when done.
+pub struct CollectionMut<'a, T: Slots> {
+    items: RefMut<'a, T>,
+    bytes: &'a Cell<usize>,
+}
+
+impl<T: Slots> Deref for CollectionMut<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.items
+    }
+}
+
+impl<T: Slots> DerefMut for CollectionMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.items
+    }
+}
+
+impl<T: Slots> Drop for CollectionMut<'_, T> {
+    fn drop(&mut self) {
+        let bytes = self.items.slots() * T::SLOT_BYTES;
+        adjust_collection_bytes(self.bytes.replace(bytes), bytes);
+    }
+}
+
+pub type Iter = Rc<RefCell<ListIter>>;
+
+// Position of a `for` loop in a list, items pushed while iterating are seen.
+#[derive(Debug)]
+pub struct ListIter {
+    pub(crate) items: List,
+    pub(crate) next: usize,
+}
+
+impl ListIter {
+    pub fn new(items: List) -> Iter {
+        Rc::new(RefCell::new(ListIter { items, next: 0 }))
+    }
+
+    pub fn next_item(&mut self) -> Option<Value> {
+        let item = self.items.borrow().get(self.next).cloned();
+        if item.is_some() {
+            self.next += 1;
+        }
+        item
+    }
+}
 
 impl Function {
     pub fn new(arity: usize, upvalue_count: usize, chunk: Chunk) -> Function {
         Function {
             arity,
             upvalue_count,
+            max_stack: 0,
+            name: None,
             chunk,
         }
     }
@@ -44,9 +188,11 @@ pub enum Value {
     Float(f64),
     Int(isize),
     Str(String),
+    List(List),
+    Map(Map),
+    Iterator(Iter),
     Closure(Closure),
     Function(Function),
-    HeapedData(HeapedData),
     Native(String),
 }
 
@@ -58,20 +204,194 @@ impl Value {
             _ => false,
         }
     }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::None => "none",
+            Value::Bool(_) => "bool",
+            Value::Float(_) => "float",
+            Value::Int(_) => "int",
+            Value::Str(_) => "str",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Iterator(_) => "iterator",
+            Value::Closure(_) => "closure",
+            Value::Function(_) => "function",
+            Value::Native(_) => "native",
+        }
+    }
+
+    fn type_error(&self, expected: &str) -> RuntimeError {
+        RuntimeError::of_kind(
+            ErrorKind::TypeError,
+            format!("expected {}, got {}", expected, self.type_name()),
+        )
+    }
+
+    pub fn expect_bool(&self) -> Result<bool, RuntimeError> {
+        match self {
+            Value::Bool(x) => Ok(*x),
+            x => Err(x.type_error("bool")),
+        }
+    }
+
+    pub fn expect_int(&self) -> Result<isize, RuntimeError> {
+        match self {
+            Value::Int(x) => Ok(*x),
+            x => Err(x.type_error("int")),
+        }
+    }
+
+    // Ints are promoted, as in arithmetic.
+    pub fn expect_float(&self) -> Result<f64, RuntimeError> {
+        match self {
+            Value::Float(x) => Ok(*x),
+            Value::Int(x) => Ok(*x as f64),
+            x => Err(x.type_error("number")),
+        }
+    }
+
+    pub fn expect_str(&self) -> Result<&str, RuntimeError> {
+        match self {
+            Value::Str(x) => Ok(x),
+            x => Err(x.type_error("str")),
+        }
+    }
+
+    pub fn expect_list(&self) -> Result<&List, RuntimeError> {
+        match self {
+            Value::List(x) => Ok(x),
+            x => Err(x.type_error("list")),
+        }
+    }
+
+    pub fn expect_map(&self) -> Result<&Map, RuntimeError> {
+        match self {
+            Value::Map(x) => Ok(x),
+            x => Err(x.type_error("map")),
+        }
+    }
+
+    // Like Display but shows what kind of value it is, strings are quoted.
+    pub fn debug_repr(&self) -> String {
+        self.render(true)
+    }
+
+    // Lists and maps are expanded onto an explicit stack rather than by
+    // recursion, one nested too deep or inside itself is cut short as [...].
+    fn render(&self, repr: bool) -> String {
+        let mut out = String::new();
+        let mut open: Vec<*const ()> = vec![];
+        let mut pending = vec![Pending::Value(self.clone())];
+        while let Some(next) = pending.pop() {
+            let x = match next {
+                Pending::Value(x) => x,
+                Pending::Text(text) => {
+                    out.push_str(&text);
+                    continue;
+                }
+                Pending::Leave => {
+                    open.pop();
+                    continue;
+                }
+            };
+            let id = match &x {
+                Value::List(xs) => Rc::as_ptr(xs) as *const (),
+                Value::Map(m) => Rc::as_ptr(m) as *const (),
+                x => {
+                    out.push_str(&x.render_scalar(repr));
+                    continue;
+                }
+            };
+            if open.len() >= MAX_PRINT_DEPTH || open.contains(&id) {
+                out.push_str(if let Value::List(_) = x {
+                    "[...]"
+                } else {
+                    "{...}"
+                });
+                continue;
+            }
+            open.push(id);
+            pending.push(Pending::Leave);
+            match &x {
+                Value::List(xs) => {
+                    out.push('[');
+                    pending.push(Pending::Text("]".to_string()));
+                    for (i, x) in xs.borrow().iter().enumerate().rev() {
+                        pending.push(Pending::Value(x.clone()));
+                        if i != 0 {
+                            pending.push(Pending::Text(", ".to_string()));
+                        }
+                    }
+                }
+                Value::Map(m) => {
+                    out.push('{');
+                    pending.push(Pending::Text("}".to_string()));
+                    for (i, (k, v)) in m.borrow().iter().enumerate().rev() {
+                        pending.push(Pending::Value(v.clone()));
+                        let key = if repr { format!("{:?}", k) } else { k.clone() };
+                        let sep = if i != 0 { ", " } else { "" };
+                        pending.push(Pending::Text(format!("{}{}: ", sep, key)));
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        out
+    }
+
+    fn render_scalar(&self, repr: bool) -> String {
+        match (self, repr) {
+            (Value::None, _) => "None".to_string(),
+            (Value::Bool(x), _) => x.to_string(),
+            (Value::Float(x), _) => format_float(*x),
+            (Value::Int(x), _) => x.to_string(),
+            (Value::Str(x), true) => format!("{:?}", x),
+            (Value::Str(x), false) => x.clone(),
+            (Value::Closure(c), true) => format!("<closure/{}>", c.function.arity),
+            (Value::Closure(_), false) => "Closure".to_string(),
+            (Value::Function(f), true) => format!("<function/{}>", f.arity),
+            (Value::Function(_), false) => "Function".to_string(),
+            (Value::Native(x), true) => format!("<native {}>", x),
+            (Value::Native(x), false) => format!("Native({})", x),
+            (Value::Iterator(_), _) => "Iterator".to_string(),
+            (Value::List(_) | Value::Map(_), _) => self.render(repr),
+        }
+    }
+
+    // Ordering used by sorting, only numbers with numbers and strings with
+    // strings are comparable.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(x), Value::Int(y)) => Some(x.cmp(y)),
+            (Value::Int(x), Value::Float(y)) => (*x as f64).partial_cmp(y),
+            (Value::Float(x), Value::Int(y)) => x.partial_cmp(&(*y as f64)),
+            (Value::Float(x), Value::Float(y)) => x.partial_cmp(y),
+            (Value::Str(x), Value::Str(y)) => Some(x.cmp(y)),
+            _ => None,
+        }
+    }
+}
+
+// The shortest digits that parse back to exactly `x`. Very large and very
+// small magnitudes use an exponent so 1e300 isn't printed as 301 digits.
+pub fn format_float(x: f64) -> String {
+    if x != 0.0 && x.is_finite() && !(1e-7..1e21).contains(&x.abs()) {
+        format!("{:e}", x)
+    } else {
+        format!("{}", x)
+    }
+}
+
+// Work left while rendering a value, see Value::render.
+enum Pending {
+    Value(Value),
+    Text(String),
+    Leave, // The innermost open list or map is done
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Value::None => write!(f, "None"),
-            Value::Bool(x) => write!(f, "{}", x),
-            Value::Float(x) => write!(f, "{}", x),
-            Value::Int(x) => write!(f, "{}", x),
-            Value::Str(x) => write!(f, "{}", x),
-            Value::Closure(_) => write!(f, "Closure"),
-            Value::Function(_) => write!(f, "Function"),
-            Value::HeapedData(x) => write!(f, "{}", x.borrow()),
-            Value::Native(x) => write!(f, "Native({})", x),
-        }
+        write!(f, "{}", self.render(false))
     }
 }
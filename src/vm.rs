@@ -1,10 +1,15 @@
 use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fmt;
+use std::mem;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 
-use crate::common::Op;
+use crate::common::{Op, FINALLY};
 use crate::native::FFI;
-use crate::value::{Closure, HeapedData, Value};
+use crate::snapshot::DeepCopy;
+use crate::value::{self, Closure, Collection, Function, HeapedData, ListIter, Value};
 
 #[derive(Clone)]
 pub struct CallFrame {
@@ -23,41 +28,468 @@ impl CallFrame {
     }
 }
 
-pub enum VMResult {
-    Ok,
+// What went wrong, so `catch (e: Kind)` can handle some errors and let the
+// rest through. Error is anything without a more specific kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
     Error,
+    TypeError,
+    NameError,
+    KeyError,
+    DivisionByZero,
+}
+
+impl ErrorKind {
+    const ALL: [ErrorKind; 5] = [
+        ErrorKind::Error,
+        ErrorKind::TypeError,
+        ErrorKind::NameError,
+        ErrorKind::KeyError,
+        ErrorKind::DivisionByZero,
+    ];
+
+    pub fn from_name(name: &str) -> Option<ErrorKind> {
+        ErrorKind::ALL
+            .into_iter()
+            .find(|k| format!("{:?}", k) == name)
+    }
+
+    pub fn from_byte(b: u8) -> Option<ErrorKind> {
+        ErrorKind::ALL.get(b as usize).copied()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl RuntimeError {
+    pub fn new(msg: impl Into<String>) -> RuntimeError {
+        RuntimeError::of_kind(ErrorKind::Error, msg)
+    }
+
+    pub fn of_kind(kind: ErrorKind, msg: impl Into<String>) -> RuntimeError {
+        RuntimeError {
+            kind,
+            message: msg.into(),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ErrorKind::Error => write!(f, "{}", self.message),
+            kind => write!(f, "{:?}: {}", kind, self.message),
+        }
+    }
+}
+
+fn binary_error(verb: &str, x: &Value, y: &Value) -> RuntimeError {
+    RuntimeError::of_kind(
+        ErrorKind::TypeError,
+        format!("can't {} {} and {}", verb, x.type_name(), y.type_name()),
+    )
+}
+
+fn undefined_error(name: &str) -> RuntimeError {
+    RuntimeError::of_kind(ErrorKind::NameError, format!("undefined variable {}", name))
+}
+
+pub fn is_equal(x: &Value, y: &Value) -> bool {
+    is_equal_within(x, y, &mut vec![])
+}
+
+// `within` holds the pairs of lists or maps being compared around these
+// values. A pair met again inside itself is taken as equal, so collections
+// containing themselves compare without recursing forever.
+fn is_equal_within(x: &Value, y: &Value, within: &mut Vec<(*const (), *const ())>) -> bool {
+    match (x, y) {
+        (Value::Int(x), Value::Int(y)) => x == y,
+        (Value::Float(x), Value::Float(y)) => x == y,
+        (Value::Int(x), Value::Float(y)) | (Value::Float(y), Value::Int(x)) => int_eq_float(*x, *y),
+        (Value::Bool(x), Value::Bool(y)) => x == y,
+        (Value::Str(x), Value::Str(y)) => x == y,
+        (Value::None, Value::None) => true,
+        // Closures are equal only to themselves, each evaluation of a lambda
+        // gets its own upvalue list.
+        (Value::Closure(x), Value::Closure(y)) => Rc::ptr_eq(&x.upvalues, &y.upvalues),
+        // Lists are equal when their items are, in order.
+        (Value::List(xs), Value::List(ys)) => {
+            let pair = (Rc::as_ptr(xs) as *const (), Rc::as_ptr(ys) as *const ());
+            if Rc::ptr_eq(xs, ys) || within.contains(&pair) {
+                return true;
+            }
+            let (xs, ys) = (xs.borrow(), ys.borrow());
+            within.push(pair);
+            let equal = xs.len() == ys.len()
+                && xs
+                    .iter()
+                    .zip(ys.iter())
+                    .all(|(x, y)| is_equal_within(x, y, within));
+            within.pop();
+            equal
+        }
+        // Maps are equal when they have the same keys with equal values.
+        (Value::Map(m), Value::Map(n)) => {
+            let pair = (Rc::as_ptr(m) as *const (), Rc::as_ptr(n) as *const ());
+            if Rc::ptr_eq(m, n) || within.contains(&pair) {
+                return true;
+            }
+            let (m, n) = (m.borrow(), n.borrow());
+            within.push(pair);
+            let equal = m.len() == n.len()
+                && m.iter()
+                    .zip(n.iter())
+                    .all(|((k, x), (l, y))| k == l && is_equal_within(x, y, within));
+            within.pop();
+            equal
+        }
+        (_, _) => false,
+    }
+}
+
+// Mixed comparisons are exact: an int equals a float only if the float holds
+// exactly that integer, so large ints which round when promoted compare unequal
+// to the rounded float. Checking through i128 avoids the saturation of `as isize`.
+fn int_eq_float(x: isize, y: f64) -> bool {
+    x as f64 == y && y as i128 == x as i128
+}
+
+// The arithmetic opcodes, also exposed as natives so operators can be used as
+// values.
+pub fn add(x: Value, y: Value) -> Result<Value, RuntimeError> {
+    Ok(match (x, y) {
+        (Value::Int(x), Value::Int(y)) => Value::Int(x + y),
+        (Value::Float(x), Value::Int(y)) => Value::Float(x + y as f64),
+        (Value::Int(x), Value::Float(y)) => Value::Float(x as f64 + y),
+        (Value::Float(x), Value::Float(y)) => Value::Float(x + y),
+        (x, y) => return Err(binary_error("add", &x, &y)),
+    })
+}
+
+pub fn subtract(x: Value, y: Value) -> Result<Value, RuntimeError> {
+    Ok(match (x, y) {
+        (Value::Int(x), Value::Int(y)) => Value::Int(x - y),
+        (Value::Float(x), Value::Int(y)) => Value::Float(x - y as f64),
+        (Value::Int(x), Value::Float(y)) => Value::Float(x as f64 - y),
+        (Value::Float(x), Value::Float(y)) => Value::Float(x - y),
+        (x, y) => return Err(binary_error("subtract", &x, &y)),
+    })
+}
+
+pub fn multiply(x: Value, y: Value) -> Result<Value, RuntimeError> {
+    Ok(match (x, y) {
+        (Value::Int(x), Value::Int(y)) => Value::Int(x * y),
+        (Value::Float(x), Value::Int(y)) => Value::Float(x * y as f64),
+        (Value::Int(x), Value::Float(y)) => Value::Float(x as f64 * y),
+        (Value::Float(x), Value::Float(y)) => Value::Float(x * y),
+        (x, y) => return Err(binary_error("multiply", &x, &y)),
+    })
+}
+
+pub fn divide(x: Value, y: Value) -> Result<Value, RuntimeError> {
+    let (x, y) = match (x, y) {
+        (Value::Int(x), Value::Int(y)) => (x as f64, y as f64),
+        (Value::Float(x), Value::Int(y)) => (x, y as f64),
+        (Value::Int(x), Value::Float(y)) => (x as f64, y),
+        (Value::Float(x), Value::Float(y)) => (x, y),
+        (x, y) => return Err(binary_error("divide", &x, &y)),
+    };
+    if y == 0.0 {
+        return Err(RuntimeError::of_kind(
+            ErrorKind::DivisionByZero,
+            "division by zero",
+        ));
+    }
+    Ok(Value::Float(x / y))
+}
+
+// The remainder takes the sign of x, as with Rust's %.
+pub fn modulo(x: Value, y: Value) -> Result<Value, RuntimeError> {
+    let zero = RuntimeError::of_kind(ErrorKind::DivisionByZero, "modulo by zero");
+    Ok(match (x, y) {
+        (Value::Int(_), Value::Int(0)) => return Err(zero),
+        (Value::Int(x), Value::Int(y)) => Value::Int(x.wrapping_rem(y)),
+        (x, y) => {
+            let (x, y) = match (x, y) {
+                (Value::Float(x), Value::Int(y)) => (x, y as f64),
+                (Value::Int(x), Value::Float(y)) => (x as f64, y),
+                (Value::Float(x), Value::Float(y)) => (x, y),
+                (x, y) => return Err(binary_error("take the remainder of", &x, &y)),
+            };
+            if y == 0.0 {
+                return Err(zero);
+            }
+            Value::Float(x % y)
+        }
+    })
+}
+
+// Numbers compare with numbers and strings with strings, see Value::compare.
+fn ordering(x: &Value, y: &Value) -> Result<Ordering, RuntimeError> {
+    x.compare(y).ok_or_else(|| binary_error("compare", x, y))
+}
+
+pub fn less(x: Value, y: Value) -> Result<Value, RuntimeError> {
+    Ok(Value::Bool(ordering(&x, &y)? == Ordering::Less))
+}
+
+pub fn greater(x: Value, y: Value) -> Result<Value, RuntimeError> {
+    Ok(Value::Bool(ordering(&x, &y)? == Ordering::Greater))
+}
+
+pub fn less_equal(x: Value, y: Value) -> Result<Value, RuntimeError> {
+    Ok(Value::Bool(ordering(&x, &y)? != Ordering::Greater))
+}
+
+pub fn greater_equal(x: Value, y: Value) -> Result<Value, RuntimeError> {
+    Ok(Value::Bool(ordering(&x, &y)? != Ordering::Less))
+}
+
+// An open try block, errors unwind the frames and the stack back to how they
+// were when it was entered and continue at its catch.
+// A handler with a kind only catches errors of that kind.
+#[derive(Clone)]
+struct Handler {
+    frames: usize,
+    stack_len: usize,
+    catch_ip: usize,
+    kind: Option<ErrorKind>,
+    finally: bool, // Gets the error's kind too, see Op::Rethrow
+}
+
+// Counters gathered by VM::run_with_stats. Allocations are the lists,
+// closures and iterators created by instructions, natives aren't counted.
+// Peak memory is the most VM::memory_in_use reached. Global lookups are the
+// global accesses which had to find their slot by name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunStats {
+    pub instructions: usize,
+    pub max_stack: usize,
+    pub max_frames: usize,
+    pub allocations: usize,
+    pub peak_memory: usize,
+    pub global_lookups: usize,
+}
+
+pub enum VMResult {
+    Ok(Value),
+    Error(RuntimeError),
+}
+
+// Everything a VM holds besides its natives, see VM::snapshot.
+pub struct VmState {
+    frames: Vec<CallFrame>,
+    current_frame: usize,
+    stack: Vec<Value>,
+    globals: HashMap<String, Value>,
+    handlers: Vec<Handler>,
+}
+
+impl VmState {
+    // The values are copied as well, so later changes on either side leave the
+    // other alone.
+    fn copy(
+        frames: &[CallFrame],
+        current_frame: usize,
+        stack: &[Value],
+        globals: &HashMap<String, Value>,
+        handlers: &[Handler],
+    ) -> VmState {
+        let mut copy = DeepCopy::default();
+        VmState {
+            frames: frames
+                .iter()
+                .map(|f| CallFrame {
+                    ip: f.ip,
+                    closure: copy.closure(&f.closure),
+                    stack_start: f.stack_start,
+                })
+                .collect(),
+            current_frame,
+            stack: stack.iter().map(|x| copy.value(x)).collect(),
+            globals: globals
+                .iter()
+                .map(|(k, v)| (k.clone(), copy.value(v)))
+                .collect(),
+            handlers: handlers.to_vec(),
+        }
+    }
+}
+
+// Global values by slot. A slot is never reused for another name, so once an
+// instruction has found its slot it can keep using it, see VM::global_slot.
+#[derive(Default)]
+struct Globals {
+    slots: HashMap<String, usize>,
+    names: Vec<String>,
+    values: Vec<Value>,
+    read_only: Vec<bool>, // Defined by the host for scripts to read only
+}
+
+impl Globals {
+    fn from_map(map: HashMap<String, Value>) -> Globals {
+        let mut globals = Globals::default();
+        for (name, value) in map {
+            globals.define(name, value);
+        }
+        globals
+    }
+
+    fn to_map(&self) -> HashMap<String, Value> {
+        self.names
+            .iter()
+            .cloned()
+            .zip(self.values.iter().cloned())
+            .collect()
+    }
+
+    // Gives the name's slot, making one the first time.
+    fn define(&mut self, name: String, value: Value) -> usize {
+        if let Some(&slot) = self.slots.get(&name) {
+            self.values[slot] = value;
+            return slot;
+        }
+        self.slots.insert(name.clone(), self.names.len());
+        self.names.push(name);
+        self.values.push(value);
+        self.read_only.push(false);
+        self.names.len() - 1
+    }
 }
 
+// Tells VMs apart in the global caches of the chunks they share.
+static NEXT_VM_ID: AtomicUsize = AtomicUsize::new(0);
+
 pub struct VM<'a> {
+    id: usize,
     frames: Vec<CallFrame>,
     current_frame: usize,
     ffi: &'a FFI,
     stack: Vec<Value>,
-    globals: HashMap<String, Value>,
+    globals: Globals,
+    handlers: Vec<Handler>,
+    stats: Option<RunStats>,
+    dbg: bool,
+    strict_numbers: bool, // Mixing ints and floats in arithmetic is an error rather than a promotion
+    memory_limit: Option<usize>,
+    memory_base: usize, // Collection bytes already in use when the VM was made
 }
 
 impl VM<'_> {
-    pub fn new(c: Closure, natives: &FFI) -> VM {
+    pub fn new(c: Closure, natives: &FFI) -> VM<'_> {
         // TODO: Make call-stack static.
         let initial_frame: CallFrame = CallFrame::new(c, 0);
         let mut frames = Vec::with_capacity(1024);
         let stack = Vec::with_capacity(1024);
         frames.push(initial_frame);
-        let vm = VM {
-            frames: frames,
+        VM {
+            id: NEXT_VM_ID.fetch_add(1, AtomicOrdering::Relaxed),
+            frames,
             ffi: natives,
             current_frame: 0,
-            stack: stack,
-            globals: HashMap::new(),
-        };
+            stack,
+            globals: Globals::default(),
+            handlers: vec![],
+            stats: None,
+            dbg: false,
+            strict_numbers: false,
+            memory_limit: None,
+            memory_base: value::collection_bytes(),
+        }
+    }
+
+    // A VM whose program starts with `globals` already defined, e.g. values
+    // the host wants to hand the script.
+    pub fn with_globals(c: Closure, natives: &FFI, globals: HashMap<String, Value>) -> VM<'_> {
+        let mut vm = VM::new(c, natives);
+        vm.globals = Globals::from_map(globals);
         vm
     }
 
+    // A global scripts can read but neither assign nor define again, e.g.
+    // configuration from the host.
+    pub fn define_read_only(&mut self, name: &str, value: Value) {
+        let slot = self.globals.define(name.to_string(), value);
+        self.globals.read_only[slot] = true;
+    }
+
+    fn check_writable(&self, slot: usize) -> Result<(), RuntimeError> {
+        if self.globals.read_only[slot] {
+            return Err(RuntimeError::new(format!(
+                "can't change read-only global {}",
+                self.globals.names[slot]
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn set_strict_numbers(&mut self, strict: bool) {
+        self.strict_numbers = strict;
+    }
+
+    // With strict numbers an int and a float can't be mixed in arithmetic,
+    // one has to be converted with int or float first.
+    pub fn check_promotion(&self, verb: &str, x: &Value, y: &Value) -> Result<(), RuntimeError> {
+        match (x, y) {
+            (Value::Int(_), Value::Float(_)) | (Value::Float(_), Value::Int(_))
+                if self.strict_numbers =>
+            {
+                Err(RuntimeError::of_kind(
+                    ErrorKind::TypeError,
+                    format!(
+                        "can't {} {} and {} with strict numbers, convert with int or float",
+                        verb,
+                        x.type_name(),
+                        y.type_name()
+                    ),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // An estimate in bytes of what the run holds: its stack and frames, and
+    // the slots of the lists and maps made since the VM was, see value::Slots.
+    pub fn memory_in_use(&self) -> usize {
+        value::collection_bytes().saturating_sub(self.memory_base)
+            + self.stack.len() * mem::size_of::<Value>()
+            + self.frames.len() * mem::size_of::<CallFrame>()
+    }
+
+    // Going over the limit is a runtime error. It is checked between
+    // instructions, so a native can go over before it is caught.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
     #[inline]
     fn stack_start(&self) -> usize {
         self.frames[self.current_frame].stack_start
     }
 
+    // Describes the functions currently being called, outermost first, by
+    // the name a let gave them. The top level script is left out. Chunks
+    // carry no line numbers yet so each frame gives its instruction offset
+    // instead.
+    pub fn stacktrace(&self) -> Vec<String> {
+        self.frames[..=self.current_frame]
+            .iter()
+            .skip(1)
+            .map(|frame| {
+                let function = &frame.closure.function;
+                let name = match &function.name {
+                    Some(name) => format!("{}/{}", name, function.arity),
+                    None => Value::Closure(frame.closure.clone()).debug_repr(),
+                };
+                format!("{} at {:#06x}", name, frame.ip)
+            })
+            .collect()
+    }
+
     #[inline]
     fn get_ip(&self) -> usize {
         self.frames[self.current_frame].ip
@@ -97,6 +529,61 @@ impl VM<'_> {
             .clone()
     }
 
+    // The operand of a global access, always a name constant.
+    fn get_global_name(&self, ip: usize) -> String {
+        match self.get_constant(self.read_byte(ip + 1) as usize) {
+            Value::Str(name) => name,
+            _ => unreachable!("global name is not a string"),
+        }
+    }
+
+    // The slot of the global named by the instruction at ip, if it is defined.
+    // Looking it up by name is left to the first time the instruction runs
+    // on this VM, later runs find it in the chunk's cache.
+    fn global_slot(&mut self, ip: usize) -> Option<usize> {
+        let idx = self.read_byte(ip + 1) as usize;
+        let cache = Rc::clone(
+            &self.frames[self.current_frame]
+                .closure
+                .function
+                .chunk
+                .global_cache,
+        );
+        if let Some(Some((id, slot))) = cache.borrow().get(idx) {
+            if *id == self.id {
+                return Some(*slot);
+            }
+        }
+
+        if let Some(stats) = &mut self.stats {
+            stats.global_lookups += 1;
+        }
+        let slot = *self.globals.slots.get(&self.get_global_name(ip))?;
+        let mut cache = cache.borrow_mut();
+        if cache.len() <= idx {
+            cache.resize(idx + 1, None);
+        }
+        cache[idx] = Some((self.id, slot));
+        Some(slot)
+    }
+
+    // Names defined with a top level let, natives aren't included.
+    pub fn global_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.globals.names.clone();
+        names.sort();
+        names
+    }
+
+    #[inline]
+    fn get_function(&self, idx: usize) -> Function {
+        self.frames[self.current_frame]
+            .closure
+            .function
+            .chunk
+            .functions[idx]
+            .clone()
+    }
+
     fn capture_upvalue(&mut self, idx: usize) -> HeapedData {
         let val = self.stack[idx].clone();
         match &self.stack[idx] {
@@ -104,7 +591,6 @@ impl VM<'_> {
                 function: c.function.clone(),
                 upvalues: Rc::clone(&c.upvalues),
             }))),
-            Value::HeapedData(x) => Rc::clone(&x),
             _ => {
                 let val_ref = Rc::new(RefCell::new(val));
                 Rc::clone(&val_ref)
@@ -113,6 +599,188 @@ impl VM<'_> {
     }
 
     pub fn run(&mut self, dbg: bool) -> VMResult {
+        self.dbg = dbg;
+        match self.execute(0) {
+            Ok(()) => VMResult::Ok(self.stack.pop().unwrap_or(Value::None)),
+            Err(e) => VMResult::Error(e),
+        }
+    }
+
+    // Runs `c` from `ip` on, keeping the globals of earlier runs. Used with
+    // Compiler::compile_more to run only the code added to a program.
+    pub fn run_at(&mut self, c: Closure, ip: usize, dbg: bool) -> VMResult {
+        self.frames.clear();
+        self.frames.push(CallFrame::new(c, 0));
+        self.frames[0].ip = ip;
+        self.current_frame = 0;
+        self.stack.clear();
+        self.handlers.clear();
+        self.run(dbg)
+    }
+
+    // Captures the VM's state, e.g. from a native to checkpoint a long running
+    // script. Values are deep copied, so the script can carry on changing its
+    // own.
+    pub fn snapshot(&self) -> VmState {
+        VmState::copy(
+            &self.frames,
+            self.current_frame,
+            &self.stack,
+            &self.globals.to_map(),
+            &self.handlers,
+        )
+    }
+
+    // Puts the VM back to a snapshot, which can be restored again later.
+    pub fn restore(&mut self, state: &VmState) {
+        let state = VmState::copy(
+            &state.frames,
+            state.current_frame,
+            &state.stack,
+            &state.globals,
+            &state.handlers,
+        );
+        self.frames = state.frames;
+        self.current_frame = state.current_frame;
+        self.stack = state.stack;
+        // The slots change, so this VM's entries in the global caches go.
+        let read_only = mem::take(&mut self.globals.read_only);
+        let names = mem::take(&mut self.globals.names);
+        self.globals = Globals::from_map(state.globals);
+        for (name, read_only) in names.into_iter().zip(read_only) {
+            if let (true, Some(&slot)) = (read_only, self.globals.slots.get(&name)) {
+                self.globals.read_only[slot] = true;
+            }
+        }
+        self.id = NEXT_VM_ID.fetch_add(1, AtomicOrdering::Relaxed);
+        self.handlers = state.handlers;
+    }
+
+    // Continues a VM restored from a snapshot taken inside a native, as if that
+    // native had just returned `result`.
+    pub fn resume(&mut self, result: Value, dbg: bool) -> VMResult {
+        self.stack.push(result);
+        self.run(dbg)
+    }
+
+    // Like run, also counting what the program did. The counting is skipped
+    // entirely by a plain run.
+    pub fn run_with_stats(&mut self, dbg: bool) -> (VMResult, RunStats) {
+        self.stats = Some(RunStats::default());
+        let result = self.run(dbg);
+        (result, self.stats.take().unwrap())
+    }
+
+    fn check_memory(&mut self) -> Result<(), RuntimeError> {
+        let used = self.memory_in_use();
+        if let Some(stats) = &mut self.stats {
+            stats.peak_memory = stats.peak_memory.max(used);
+        }
+        match self.memory_limit {
+            Some(limit) if used > limit => Err(RuntimeError::new(format!(
+                "memory limit of {} bytes exceeded",
+                limit
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn count_allocation(&mut self) {
+        if let Some(stats) = &mut self.stats {
+            stats.allocations += 1;
+        }
+    }
+
+    // Calls `f` from outside the run loop (e.g. from a native, or from the host
+    // after run has finished) and runs it to completion. A failed call leaves
+    // the VM as it found it so it can be called into again.
+    pub fn call_value(&mut self, f: &Value, args: &[Value]) -> Result<Value, RuntimeError> {
+        let (base, stack_len) = (self.frames.len(), self.stack.len());
+        self.stack.extend(args.iter().cloned());
+        let result = self.call(f.clone(), args.len()).and_then(|()| {
+            if self.frames.len() > base {
+                self.execute(base)?;
+            }
+            Ok(self.stack.pop().unwrap())
+        });
+        if result.is_err() {
+            self.frames.truncate(base);
+            self.current_frame = base.saturating_sub(1);
+            self.stack.truncate(stack_len);
+        }
+        result
+    }
+
+    // Calls `f` with the top `nargs` values of the stack. Closures get a new
+    // frame which the run loop picks up, natives are called right away.
+    fn call(&mut self, f: Value, nargs: usize) -> Result<(), RuntimeError> {
+        match f {
+            Value::Closure(c) => {
+                if c.function.arity != nargs {
+                    return Err(RuntimeError::of_kind(
+                        ErrorKind::TypeError,
+                        format!("expected {} arguments, got {}", c.function.arity, nargs),
+                    ));
+                }
+                // A function built by hand may not know its max_stack.
+                self.stack
+                    .reserve(c.function.max_stack.saturating_sub(nargs));
+                self.frames
+                    .push(CallFrame::new(c, self.stack.len() - nargs));
+                self.current_frame = self.frames.len() - 1;
+                Ok(())
+            }
+
+            Value::Native(name) => {
+                let args: Vec<Value> = self.stack.drain(self.stack.len() - nargs..).collect();
+                let ffi = self.ffi;
+                let result = ffi.call(&name, self, &args)?;
+                self.stack.push(result);
+                Ok(())
+            }
+
+            f => Err(RuntimeError::of_kind(
+                ErrorKind::TypeError,
+                format!("{} is not callable", f),
+            )),
+        }
+    }
+
+    // Runs until the frame at `base` returns. Errors go to the innermost try
+    // entered since then, anything else is passed on to the caller.
+    fn execute(&mut self, base: usize) -> Result<(), RuntimeError> {
+        loop {
+            match self.execute_frames(base) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    // Tries skipped over are left along with the frames.
+                    let found = self
+                        .handlers
+                        .iter()
+                        .rposition(|h| h.frames > base && h.kind.is_none_or(|k| k == e.kind));
+                    let Some(i) = found else {
+                        while matches!(self.handlers.last(), Some(h) if h.frames > base) {
+                            self.handlers.pop();
+                        }
+                        return Err(e);
+                    };
+                    let h = self.handlers.remove(i);
+                    self.handlers.truncate(i);
+                    self.frames.truncate(h.frames);
+                    self.current_frame = h.frames - 1;
+                    self.stack.truncate(h.stack_len);
+                    self.stack.push(Value::Str(e.message));
+                    if h.finally {
+                        self.stack.push(Value::Int(e.kind as isize));
+                    }
+                    self.set_ip(h.catch_ip);
+                }
+            }
+        }
+    }
+
+    fn execute_frames(&mut self, base: usize) -> Result<(), RuntimeError> {
         while self.get_ip()
             < self.frames[self.current_frame]
                 .closure
@@ -122,7 +790,15 @@ impl VM<'_> {
                 .len()
         {
             let ip = self.get_ip();
-            if dbg {
+            if let Some(stats) = &mut self.stats {
+                stats.instructions += 1;
+                stats.max_stack = stats.max_stack.max(self.stack.len());
+                stats.max_frames = stats.max_frames.max(self.frames.len());
+            }
+            if self.stats.is_some() || self.memory_limit.is_some() {
+                self.check_memory()?;
+            }
+            if self.dbg {
                 // Debug Info
                 println!("-");
                 print!("Stack {}: [ ", self.frames[self.current_frame].stack_start);
@@ -149,13 +825,16 @@ impl VM<'_> {
                 Op::Return => {
                     let result = self.stack.pop().unwrap();
                     let drain_from = self.frames.pop().unwrap().stack_start;
-                    if self.frames.len() == 0 {
-                        return VMResult::Ok;
-                    }
-                    self.stack.drain(drain_from..self.stack.len());
-
-                    self.current_frame -= 1;
+                    self.stack.truncate(drain_from);
                     self.stack.push(result);
+                    self.current_frame = self.frames.len().saturating_sub(1);
+                    // Returning from inside a try leaves it.
+                    while matches!(self.handlers.last(), Some(h) if h.frames > self.frames.len()) {
+                        self.handlers.pop();
+                    }
+                    if self.frames.len() == base {
+                        return Ok(());
+                    }
                 }
 
                 Op::Pop => {
@@ -168,13 +847,23 @@ impl VM<'_> {
                     self.offset_ip(1);
                 }
 
+                Op::LoadFalse => {
+                    self.stack.push(Value::Bool(false));
+                    self.offset_ip(1);
+                }
+
                 Op::Negate => {
                     let x = self.stack.pop().unwrap();
                     match x {
                         Value::Bool(x) => self.stack.push(Value::Bool(!x)),
                         Value::Float(x) => self.stack.push(Value::Float(-x)),
                         Value::Int(x) => self.stack.push(Value::Int(-x)),
-                        _ => todo!("runtime error"),
+                        x => {
+                            return Err(RuntimeError::of_kind(
+                                ErrorKind::TypeError,
+                                format!("can't negate {}", x.type_name()),
+                            ))
+                        }
                     }
                     self.offset_ip(1);
                 }
@@ -182,82 +871,104 @@ impl VM<'_> {
                 Op::IsEqual => {
                     let x = self.stack.pop().unwrap();
                     let y = self.stack.pop().unwrap();
-                    match (x, y) {
-                        (Value::Int(x), Value::Int(y)) => self.stack.push(Value::Bool(x == y)),
-                        (Value::Float(x), Value::Float(y)) => self.stack.push(Value::Bool(x == y)),
-                        (Value::Bool(x), Value::Bool(y)) => self.stack.push(Value::Bool(x == y)),
-                        (_, _) => self.stack.push(Value::Bool(false)),
-                    }
+                    self.stack.push(Value::Bool(is_equal(&x, &y)));
                     self.offset_ip(1);
                 }
 
                 Op::Add => {
                     let y = self.stack.pop().unwrap();
                     let x = self.stack.pop().unwrap();
-                    match (x, y) {
-                        (Value::Int(x), Value::Int(y)) => self.stack.push(Value::Int(x + y)),
-                        (Value::Float(x), Value::Int(y)) => {
-                            self.stack.push(Value::Float(x + y as f64))
-                        }
-                        (Value::Int(x), Value::Float(y)) => {
-                            self.stack.push(Value::Float(x as f64 + y))
-                        }
-                        (Value::Float(x), Value::Float(y)) => self.stack.push(Value::Float(x + y)),
-                        _ => todo!("runtime error"),
-                    }
+                    self.check_promotion("add", &x, &y)?;
+                    self.stack.push(add(x, y)?);
                     self.offset_ip(1);
                 }
 
                 Op::Subtract => {
                     let y = self.stack.pop().unwrap();
                     let x = self.stack.pop().unwrap();
-                    match (x, y) {
-                        (Value::Int(x), Value::Int(y)) => self.stack.push(Value::Int(x - y)),
-                        (Value::Float(x), Value::Int(y)) => {
-                            self.stack.push(Value::Float(x - y as f64))
-                        }
-                        (Value::Int(x), Value::Float(y)) => {
-                            self.stack.push(Value::Float(x as f64 - y))
-                        }
-                        (Value::Float(x), Value::Float(y)) => self.stack.push(Value::Float(x - y)),
-                        _ => todo!("runtime error"),
-                    }
+                    self.check_promotion("subtract", &x, &y)?;
+                    self.stack.push(subtract(x, y)?);
                     self.offset_ip(1);
                 }
 
                 Op::Multiply => {
                     let y = self.stack.pop().unwrap();
                     let x = self.stack.pop().unwrap();
-                    match (x, y) {
-                        (Value::Int(x), Value::Int(y)) => self.stack.push(Value::Int(x * y)),
-                        (Value::Float(x), Value::Int(y)) => {
-                            self.stack.push(Value::Float(x * y as f64))
-                        }
-                        (Value::Int(x), Value::Float(y)) => {
-                            self.stack.push(Value::Float(x as f64 * y))
-                        }
-                        (Value::Float(x), Value::Float(y)) => self.stack.push(Value::Float(x * y)),
-                        _ => todo!("runtime error"),
-                    }
+                    self.check_promotion("multiply", &x, &y)?;
+                    self.stack.push(multiply(x, y)?);
                     self.offset_ip(1);
                 }
 
                 Op::Divide => {
                     let y = self.stack.pop().unwrap();
                     let x = self.stack.pop().unwrap();
-                    match (x, y) {
-                        (Value::Int(x), Value::Int(y)) => {
-                            self.stack.push(Value::Float(x as f64 / y as f64))
-                        }
-                        (Value::Float(x), Value::Int(y)) => {
-                            self.stack.push(Value::Float(x / y as f64))
-                        }
-                        (Value::Int(x), Value::Float(y)) => {
-                            self.stack.push(Value::Float(x as f64 / y))
+                    self.check_promotion("divide", &x, &y)?;
+                    self.stack.push(divide(x, y)?);
+                    self.offset_ip(1);
+                }
+
+                Op::Modulo => {
+                    let y = self.stack.pop().unwrap();
+                    let x = self.stack.pop().unwrap();
+                    self.check_promotion("take the remainder of", &x, &y)?;
+                    self.stack.push(modulo(x, y)?);
+                    self.offset_ip(1);
+                }
+
+                Op::Less => {
+                    let y = self.stack.pop().unwrap();
+                    let x = self.stack.pop().unwrap();
+                    self.stack.push(less(x, y)?);
+                    self.offset_ip(1);
+                }
+
+                Op::Greater => {
+                    let y = self.stack.pop().unwrap();
+                    let x = self.stack.pop().unwrap();
+                    self.stack.push(greater(x, y)?);
+                    self.offset_ip(1);
+                }
+
+                Op::LessEqual => {
+                    let y = self.stack.pop().unwrap();
+                    let x = self.stack.pop().unwrap();
+                    self.stack.push(less_equal(x, y)?);
+                    self.offset_ip(1);
+                }
+
+                Op::GreaterEqual => {
+                    let y = self.stack.pop().unwrap();
+                    let x = self.stack.pop().unwrap();
+                    self.stack.push(greater_equal(x, y)?);
+                    self.offset_ip(1);
+                }
+
+                Op::GetIter => {
+                    self.count_allocation();
+                    let iter = match self.stack.pop().unwrap() {
+                        Value::List(xs) => ListIter::new(xs),
+                        Value::Iterator(it) => it,
+                        x => {
+                            return Err(RuntimeError::of_kind(
+                                ErrorKind::TypeError,
+                                format!("can't iterate over {}", x.type_name()),
+                            ))
                         }
-                        (Value::Float(x), Value::Float(y)) => self.stack.push(Value::Float(x / y)),
-                        _ => todo!("runtime error"),
-                    }
+                    };
+                    self.stack.push(Value::Iterator(iter));
+                    self.offset_ip(1);
+                }
+
+                // Leaves the iterator in place and pushes the next item and
+                // whether there was one, the item is None once it runs out.
+                Op::IterNext => {
+                    let item = match self.stack.last() {
+                        Some(Value::Iterator(it)) => it.borrow_mut().next_item(),
+                        _ => unreachable!("iter_next without an iterator"),
+                    };
+                    let more = item.is_some();
+                    self.stack.push(item.unwrap_or(Value::None));
+                    self.stack.push(Value::Bool(more));
                     self.offset_ip(1);
                 }
 
@@ -269,30 +980,43 @@ impl VM<'_> {
                     self.offset_ip(2);
                 }
 
-                Op::SetGlobal => {
-                    let name = &self.get_constant(self.read_byte(ip + 1) as usize);
-                    let val = self.stack.pop().unwrap();
-                    if let Value::Str(x) = name {
-                        self.globals.insert(x.clone(), val);
-                    } else {
-                        todo!("Invalid Set");
+                Op::DefineGlobal => {
+                    match self.global_slot(ip) {
+                        Some(slot) => {
+                            self.check_writable(slot)?;
+                            self.globals.values[slot] = self.stack.pop().unwrap();
+                        }
+                        None => {
+                            let val = self.stack.pop().unwrap();
+                            self.globals.define(self.get_global_name(ip), val);
+                        }
                     }
                     self.offset_ip(2);
                 }
 
+                Op::SetGlobal => {
+                    let Some(slot) = self.global_slot(ip) else {
+                        return Err(undefined_error(&self.get_global_name(ip)));
+                    };
+                    self.check_writable(slot)?;
+                    self.globals.values[slot] = self.stack.pop().unwrap();
+                    self.offset_ip(2);
+                }
+
                 Op::GetGlobal => {
-                    let name = &self.get_constant(self.read_byte(ip + 1) as usize);
-                    if let Value::Str(x) = name {
-                        if self.ffi.has(x) {
-                            self.stack.push(Value::Native(x.clone()));
-                        } else if self.globals.contains_key(x) {
-                            let val = self.globals.get(x).unwrap();
+                    // User globals shadow native constants, which shadow natives.
+                    if let Some(slot) = self.global_slot(ip) {
+                        self.stack.push(self.globals.values[slot].clone());
+                    } else {
+                        let name = self.get_global_name(ip);
+                        if let Some(val) = self.ffi.value(&name) {
                             self.stack.push(val.clone());
+                        } else if self.ffi.has(&name) {
+                            self.stack.push(Value::Native(name));
+                        } else {
+                            return Err(undefined_error(&name));
                         }
-                    } else {
-                        todo!("Invalid Get");
                     }
-
                     self.offset_ip(2);
                 }
 
@@ -310,6 +1034,20 @@ impl VM<'_> {
                     self.offset_ip(2);
                 }
 
+                Op::PopN => {
+                    let n = self.read_byte(ip + 1) as usize;
+                    self.stack.truncate(self.stack.len() - n);
+                    self.offset_ip(2);
+                }
+
+                Op::MakeList => {
+                    self.count_allocation();
+                    let n = self.read_byte(ip + 1) as usize;
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(Value::List(Collection::new(items)));
+                    self.offset_ip(2);
+                }
+
                 Op::SetUpvalue => {
                     let idx = self.read_byte(ip + 1) as usize;
                     self.offset_ip(2);
@@ -320,37 +1058,36 @@ impl VM<'_> {
 
                 Op::GetUpvalue => {
                     let idx = self.read_byte(ip + 1) as usize;
-                    self.stack.push(Value::HeapedData(Rc::clone(
-                        &self.frames[self.current_frame].closure.upvalues.borrow()[idx],
-                    )));
+                    let val = self.frames[self.current_frame].closure.upvalues.borrow()[idx]
+                        .borrow()
+                        .clone();
+                    self.stack.push(val);
                     self.offset_ip(2);
                 }
 
                 Op::Call => {
                     let nargs = self.read_byte(ip + 1) as usize;
-                    let mut f = self.stack.pop().unwrap();
-
-                    if let Value::HeapedData(x) = f {
-                        f = x.borrow().clone();
-                    }
-
+                    let f = self.stack.pop().unwrap();
                     self.offset_ip(2);
-                    match f {
-                        Value::Closure(c) => {
-                            self.current_frame += 1;
-                            self.frames
-                                .push(CallFrame::new(c, self.stack.len() - nargs));
-
-                            // TODO: Check
-                        }
-
-                        Value::Native(name) => {
-                            let result = self.ffi.call(&name, &self.stack.pop().unwrap());
-                            self.stack.push(result);
-                        }
+                    self.call(f, nargs)?;
+                }
 
-                        _ => {
-                            todo!("runtime error");
+                // Calls in place of the current frame, which returns whatever
+                // the callee does. A native runs as a plain call, seeing the
+                // frames as they are, and the return after it ends the frame.
+                Op::TailCall => {
+                    let nargs = self.read_byte(ip + 1) as usize;
+                    let f = self.stack.pop().unwrap();
+                    if !matches!(f, Value::Closure(_)) {
+                        self.offset_ip(2);
+                        self.call(f, nargs)?;
+                    } else {
+                        let start = self.frames.pop().unwrap().stack_start;
+                        self.stack.drain(start..self.stack.len() - nargs);
+                        self.current_frame = self.frames.len().saturating_sub(1);
+                        self.call(f, nargs)?;
+                        if self.frames.len() == base {
+                            return Ok(());
                         }
                     }
                 }
@@ -365,48 +1102,95 @@ impl VM<'_> {
                     }
                 }
 
+                Op::JumpIfNotNone => {
+                    let offset = self.read_byte_double(ip + 1);
+                    if let Some(Value::None) = self.stack.last() {
+                        self.stack.pop();
+                        self.offset_ip(3);
+                    } else {
+                        self.offset_ip(offset);
+                    }
+                }
+
                 Op::Jump => {
                     let offset = self.read_byte_double(ip + 1);
                     self.offset_ip(offset);
                 }
 
+                Op::PushHandler => {
+                    let kind = self.read_byte(ip + 3);
+                    self.handlers.push(Handler {
+                        frames: self.frames.len(),
+                        stack_len: self.stack.len(),
+                        catch_ip: self.read_byte_double(ip + 1),
+                        kind: ErrorKind::from_byte(kind),
+                        finally: kind == FINALLY,
+                    });
+                    self.offset_ip(4);
+                }
+
+                Op::PopHandler => {
+                    self.handlers.pop();
+                    self.offset_ip(1);
+                }
+
+                Op::Dup => {
+                    let x = self.stack.last().unwrap().clone();
+                    self.stack.push(x);
+                    self.offset_ip(1);
+                }
+
+                Op::Swap => {
+                    let n = self.stack.len();
+                    self.stack.swap(n - 1, n - 2);
+                    self.offset_ip(1);
+                }
+
+                // Raises again the error a finally block ran for.
+                Op::Rethrow => {
+                    let kind = self.stack.pop().unwrap();
+                    let message = self.stack.pop().unwrap();
+                    let (Value::Int(kind), Value::Str(message)) = (kind, message) else {
+                        unreachable!("rethrow without an error")
+                    };
+                    let kind = ErrorKind::from_byte(kind as u8).unwrap();
+                    return Err(RuntimeError::of_kind(kind, message));
+                }
+
                 Op::AbsJump => {
                     let offset = self.read_byte_double(ip + 1);
                     self.set_ip(offset);
                 }
 
                 Op::MakeClosure => {
+                    self.count_allocation();
                     let idx = self.read_byte(ip + 1);
-                    if let Value::Function(f) = self.get_constant(idx as usize) {
-                        let upvalue_count = f.upvalue_count;
-                        let closure = Closure::new(f);
-                        let upvalues = Rc::clone(&closure.upvalues);
-                        self.stack.push(Value::Closure(closure));
-                        self.offset_ip(2);
+                    let f = self.get_function(idx as usize);
+                    let upvalue_count = f.upvalue_count;
+                    let closure = Closure::new(f);
+                    let upvalues = Rc::clone(&closure.upvalues);
+                    self.stack.push(Value::Closure(closure));
+                    self.offset_ip(2);
 
-                        for _ in 0..upvalue_count {
-                            let lip = self.get_ip();
-                            let is_local = self.read_byte(lip);
-                            let idx = self.read_byte(lip + 1) as usize;
-                            // TODO: Upvalues are cloned
-                            if is_local != 0 {
-                                upvalues.borrow_mut().push(self.capture_upvalue(
-                                    self.frames[self.current_frame].stack_start + idx,
-                                ));
-                            } else {
-                                upvalues.borrow_mut().push(Rc::clone(
-                                    &self.frames[self.current_frame].closure.upvalues.borrow()[idx],
-                                ));
-                            }
-                            self.offset_ip(2);
+                    for _ in 0..upvalue_count {
+                        let lip = self.get_ip();
+                        let is_local = self.read_byte(lip);
+                        let idx = self.read_byte(lip + 1) as usize;
+                        // TODO: Upvalues are cloned
+                        if is_local != 0 {
+                            upvalues.borrow_mut().push(self.capture_upvalue(
+                                self.frames[self.current_frame].stack_start + idx,
+                            ));
+                        } else {
+                            upvalues.borrow_mut().push(Rc::clone(
+                                &self.frames[self.current_frame].closure.upvalues.borrow()[idx],
+                            ));
                         }
-                        // self.stack.push(Value::Closure(closure));
-                    } else {
-                        todo!("Can only make functions into closure")
+                        self.offset_ip(2);
                     }
                 }
             }
         }
-        VMResult::Ok
+        Ok(())
     }
 }
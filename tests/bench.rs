@@ -0,0 +1,33 @@
+// Timings of the recursive programs the chunk layout is tuned for. Ignored by
+// default, run them with
+// cargo test --release --test bench -- --ignored --nocapture
+use std::time::Instant;
+
+use bonsai::value::Value;
+
+mod common;
+
+fn time(name: &str, src: &str) -> Value {
+    let ffi = common::ffi();
+    let start = Instant::now();
+    let result = common::run_with(src, &ffi);
+    println!("{}: {:?}", name, start.elapsed());
+    result
+}
+
+#[test]
+#[ignore]
+fn recursive_calls() {
+    let fib = time(
+        "fib 25",
+        "let fib = n -> if (n < 2) then n else ((fib (n - 1)) + (fib (n - 2)))\nfib 25",
+    );
+    assert!(matches!(fib, Value::Int(75025)));
+
+    let sum = time(
+        "sum 1000000",
+        "let s = n a -> if (n == 0) then (return a) else (return (s (n - 1) (a + n)))\n\
+         s 1000000 0",
+    );
+    assert!(matches!(sum, Value::Int(500000500000)));
+}
@@ -0,0 +1,136 @@
+use std::io::Write;
+use std::process::Stdio;
+
+mod common;
+
+use common::bonsai;
+
+// Feeds `input` to the REPL, giving its output without the prompts and its
+// errors.
+fn repl(input: &str) -> (String, String) {
+    let mut child = bonsai()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stdout = stdout.replace(">> ", "").replace(".. ", "");
+    (stdout, String::from_utf8(output.stderr).unwrap())
+}
+
+// --check compiles scripts without running them and fails on a bad one.
+#[test]
+fn check() {
+    let good = bonsai()
+        .args(["--check", "tests/check/good.bns"])
+        .output()
+        .unwrap();
+    assert!(good.status.success());
+    let stdout = String::from_utf8(good.stdout).unwrap();
+    assert!(!stdout.contains("only printed when run"), "{}", stdout);
+
+    let bad = bonsai()
+        .args(["--check", "tests/check/bad.bns"])
+        .output()
+        .unwrap();
+    assert!(!bad.status.success());
+    assert!(String::from_utf8(bad.stderr)
+        .unwrap()
+        .contains("Parse Error"));
+}
+
+// A line ending in a backslash makes the REPL read the next line into the
+// same input.
+#[test]
+fn repl_backslash_continues() {
+    let (out, err) = repl("print (1 + \\\n2)\nlet x = 4 \\\n  * 2\nprint x\n");
+    assert_eq!(err, "");
+    assert_eq!(out, "3\n8\n");
+}
+
+// So does a line ending in an infix operator after an operand, while an
+// operator given as a value ends its line.
+#[test]
+fn repl_infix_operator_continues() {
+    let (out, err) = repl("let a = 2 +\n3\nprint a\nlet add = +\nprint (add 1 2)\n");
+    assert_eq!(err, "");
+    assert_eq!(out, "5\n3\n");
+}
+
+// The REPL echoes results with debug_repr while print uses Display.
+#[test]
+fn repl_echo_quotes_strings() {
+    let (out, err) = repl("\"abc\"\nprint \"abc\"\n[\"x\", 1]\n");
+    assert_eq!(err, "");
+    assert_eq!(out, "\"abc\"\nabc\n[\"x\", 1]\n");
+}
+
+// A script reads everything piped to it with read_all and splits it into lines.
+#[test]
+fn read_all_from_stdin() {
+    let count = |input: &str| {
+        let mut child = bonsai()
+            .arg("tests/stdin/count_lines.bns")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        stdout.lines().last().unwrap().to_string()
+    };
+    assert_eq!(count("one\ntwo\nthree\n"), "3");
+    // No newline after the last line, and an empty line in between.
+    assert_eq!(count("one\n\ntwo"), "2");
+    assert_eq!(count(""), "0");
+}
+
+// The flags decide whether errors are colored, and NO_COLOR only stops the
+// colors Auto would use. Output to a pipe is never colored under Auto.
+#[test]
+fn color_flags() {
+    let stderr = |args: &[&str], no_color: bool| {
+        let mut cmd = bonsai();
+        if no_color {
+            cmd.env("NO_COLOR", "1");
+        }
+        let output = cmd
+            .args(args)
+            .arg("tests/fail/uncaught.bns")
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+    let colored = "\x1b[31mRuntime Error:\x1b[0m";
+
+    assert!(stderr(&["--color=always"], false).starts_with(colored));
+    assert!(stderr(&["--color=always"], true).starts_with(colored));
+    for (args, no_color) in [
+        (&["--color=never"][..], false),
+        (&["--no-color"], false),
+        (&["--color=always", "--no-color"], false),
+        (&[], false),
+        (&["--color=auto"], true),
+        (&[], true),
+    ] {
+        let err = stderr(args, no_color);
+        assert!(err.starts_with("Runtime Error:"), "{:?}: {:?}", args, err);
+        assert!(!err.contains('\x1b'), "{:?}: {:?}", args, err);
+    }
+}
@@ -0,0 +1,47 @@
+use std::env;
+
+use bonsai::color::{self, ColorChoice, RED};
+
+mod common;
+
+// --color=never output is free of ANSI escapes and --color=always output has
+// them, for both errors and disassembly.
+#[test]
+fn color_choice() {
+    let chunk = common::compile("let f = x -> x + 1").chunk;
+
+    color::set(ColorChoice::parse("never").unwrap());
+    assert_eq!(color::paint("Runtime Error:", RED), "Runtime Error:");
+    assert!(!chunk.styled_disassembly(color::enabled()).contains('\x1b'));
+
+    color::set(ColorChoice::parse("always").unwrap());
+    assert_eq!(
+        color::paint("Runtime Error:", RED),
+        "\x1b[31mRuntime Error:\x1b[0m"
+    );
+    let text = chunk.styled_disassembly(color::enabled());
+    assert!(text.contains("\x1b[33mget_local\x1b[0m 0x00"), "{}", text);
+
+    // Colors never leak into the plain disassembly tools work with.
+    assert_eq!(chunk.disassembly(), chunk.styled_disassembly(false));
+    assert_eq!(ColorChoice::parse("sometimes"), None);
+}
+
+// Auto colors a terminal unless NO_COLOR is set to something, while an
+// explicit choice ignores NO_COLOR.
+#[test]
+fn no_color() {
+    env::remove_var("NO_COLOR");
+    assert!(ColorChoice::Auto.resolve(true));
+    assert!(!ColorChoice::Auto.resolve(false));
+
+    env::set_var("NO_COLOR", "1");
+    assert!(!ColorChoice::Auto.resolve(true));
+    assert!(ColorChoice::Always.resolve(true));
+    assert!(!ColorChoice::Never.resolve(true));
+
+    // An empty NO_COLOR counts as unset.
+    env::set_var("NO_COLOR", "");
+    assert!(ColorChoice::Auto.resolve(true));
+    env::remove_var("NO_COLOR");
+}
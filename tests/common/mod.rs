@@ -0,0 +1,51 @@
+// Helpers shared by the integration tests, each test file uses some of them.
+#![allow(dead_code)]
+
+use std::path::Path;
+use std::process::Command;
+
+use bonsai::compiler::Compiler;
+use bonsai::native::FFI;
+use bonsai::parser::ParserContext;
+use bonsai::stdlib;
+use bonsai::value::{Closure, Function, Value};
+use bonsai::vm::{RuntimeError, VMResult, VM};
+
+pub fn compile(src: &str) -> Function {
+    let core = bonsai::parse(src, &ParserContext::default_bonsai()).unwrap();
+    Compiler::new(false).compile_program(&core).unwrap()
+}
+
+// An FFI with the standard library, for natives of a test's own to join.
+pub fn ffi() -> FFI {
+    let mut ffi = FFI::new();
+    stdlib::install(&mut ffi);
+    ffi
+}
+
+pub fn try_run_with(src: &str, ffi: &FFI) -> Result<Value, RuntimeError> {
+    match VM::new(Closure::new(compile(src)), ffi).run(false) {
+        VMResult::Ok(x) => Ok(x),
+        VMResult::Error(e) => Err(e),
+    }
+}
+
+pub fn run_with(src: &str, ffi: &FFI) -> Value {
+    try_run_with(src, ffi).unwrap_or_else(|e| panic!("{} failed: {}", src, e))
+}
+
+pub fn try_run(src: &str) -> Result<Value, RuntimeError> {
+    try_run_with(src, &ffi())
+}
+
+pub fn run(src: &str) -> Value {
+    run_with(src, &ffi())
+}
+
+// The bonsai binary, run from the crate root so tests/ paths resolve.
+pub fn bonsai() -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_bonsai"));
+    cmd.current_dir(Path::new(env!("CARGO_MANIFEST_DIR")))
+        .env_remove("NO_COLOR");
+    cmd
+}
@@ -0,0 +1,243 @@
+use bonsai::common::{Chunk, Instruction, Op};
+use bonsai::compiler::Compiler;
+use bonsai::parser::ParserContext;
+use bonsai::value::{Closure, Function, Value};
+use bonsai::vm::{VMResult, VM};
+
+mod common;
+
+use common::compile;
+
+// The instruction names of a chunk, without their operands.
+fn instructions(chunk: &Chunk) -> Vec<String> {
+    let mut names = vec![];
+    let mut i = 0;
+    while i < chunk.code.len() {
+        let (text, len) = chunk.disassemble_at(i);
+        names.push(text.split(' ').next().unwrap().to_string());
+        i += len;
+    }
+    names
+}
+
+// The first lambda of the program.
+fn lambda(src: &str) -> Function {
+    compile(src).chunk.functions[0].clone()
+}
+
+// true and false are literals loaded by their own instructions.
+#[test]
+fn booleans_need_no_constants() {
+    let f = compile("[true, false]");
+    let code = f.chunk.disassembly();
+    assert!(code.contains("load_true"), "{}", code);
+    assert!(code.contains("load_false"), "{}", code);
+    assert!(f.chunk.constants.is_empty(), "{:?}", f.chunk.constants);
+}
+
+// A chained comparison keeps its middle operand with dup instead of
+// evaluating it a second time.
+#[test]
+fn chained_comparisons_dup_the_middle() {
+    let code = compile("1 < 2 < 3").chunk.disassembly();
+    assert!(code.contains("dup"), "{}", code);
+    assert!(matches!(common::run("1 < 2 < 3"), Value::Bool(true)));
+
+    let counted = common::run("let n = 0\nlet f = x -> {\n n = n + 1\n x\n}\n1 < (f 5) < 9\nn");
+    assert!(matches!(counted, Value::Int(1)), "{:?}", counted);
+
+    // A single comparison has nothing to keep.
+    let code = compile("1 < 2").chunk.disassembly();
+    assert!(!code.contains("dup"), "{}", code);
+}
+
+// Decodes a small program into instructions and checks their opcodes and
+// operands, including a closure's upvalue pairs and a jump's 2-byte target.
+#[test]
+fn decode() {
+    let f = compile("let adder = x -> (y -> x + y); if (adder 1 2) then 3 else 4");
+    let program = f.chunk.instructions();
+    let ops: Vec<Op> = program.iter().map(|x| x.op).collect();
+    assert_eq!(
+        ops,
+        [
+            Op::MakeClosure,
+            Op::DefineGlobal,
+            Op::LoadConstant,
+            Op::LoadConstant,
+            Op::GetGlobal,
+            Op::Call,
+            Op::JumpIfFalse,
+            Op::LoadConstant,
+            Op::Jump,
+            Op::LoadConstant,
+            Op::Return,
+        ]
+    );
+    assert_eq!(program[5].operands, [2]);
+    // The jump over the then branch skips itself, the 2-byte load and the jump.
+    assert_eq!(program[6].operands, [8]);
+    assert_eq!(program[6].size, 3);
+    // The sizes add up to the whole chunk.
+    let total: usize = program.iter().map(|x| x.size).sum();
+    assert_eq!(total, f.chunk.code.len());
+
+    // The inner lambda captures x, the outer lambda's local 0.
+    let outer = &f.chunk.functions[0].chunk;
+    assert_eq!(
+        outer.decode_at(0),
+        Instruction {
+            op: Op::MakeClosure,
+            operands: vec![0, 1, 0],
+            size: 4
+        }
+    );
+    let inner = outer.functions[0].chunk.instructions();
+    assert_eq!(inner[0].op, Op::GetUpvalue);
+    assert_eq!(inner[0].operands, [0]);
+}
+
+// Each function's chunk is listed under the chunk that makes closures of it.
+#[test]
+fn disassembly_nests_functions() {
+    let text = compile("let adder = x -> (y -> x + y); (adder 1) 2")
+        .chunk
+        .disassembly();
+    let outer = text.find("\nFunction 0x00 (<function/1>):\n").unwrap();
+    let inner = text.find("\n    Function 0x00 (<function/1>):\n").unwrap();
+    assert!(outer < inner);
+    // The inner lambda reads x as an upvalue captured from the outer one.
+    assert!(text[outer..inner].contains("make_closure 0x00 (<function/1>) local 0"));
+    assert!(text[inner..].contains("        | 0x00 : get_upvalue 0x00"));
+}
+
+// The inlined bytecode has no call left for the helper and gives the same
+// result as the called one.
+#[test]
+fn inlining() {
+    let ffi = common::ffi();
+    let compile_with = |inline_limit| {
+        let src = "let sq = x -> x * x; let n = 7; sq n + 1";
+        let core = bonsai::parse(src, &ParserContext::default_bonsai()).unwrap();
+        let mut cc = Compiler::new(false);
+        cc.set_inline_limit(inline_limit);
+        cc.compile_program(&core).unwrap()
+    };
+    let run = |f: Function| match VM::new(Closure::new(f), &ffi).run(false) {
+        VMResult::Ok(x) => x,
+        VMResult::Error(e) => panic!("{}", e),
+    };
+
+    let called = compile_with(0);
+    let inlined = compile_with(8);
+    let (called_ops, inlined_ops) = (instructions(&called.chunk), instructions(&inlined.chunk));
+    assert!(called_ops.contains(&"call".to_string()));
+    assert!(!inlined_ops.contains(&"call".to_string()));
+    // sq itself is still defined, only the call to it is replaced.
+    assert!(inlined_ops.contains(&"make_closure".to_string()));
+    assert!(matches!(
+        (run(called), run(inlined)),
+        (Value::Int(50), Value::Int(50))
+    ));
+}
+
+// The stack depth the compiler records, against counts worked out by hand
+// from the bytecode.
+#[test]
+fn max_stack() {
+    // The argument x, then a copy of x and 1 for the addition.
+    assert_eq!(lambda("let f = x -> x + 1").max_stack, 3);
+
+    // a b c, then copies of a and b, then their sum and c.
+    assert_eq!(lambda("let f = a b c -> a + b + c").max_stack, 5);
+
+    // n, then n and 0 for the comparison. The else branch has n with n
+    // and 1, then n with n - 1 and f for the call.
+    let fact = lambda("let f = n -> if (n == 0) then 1 else (n * (f (n - 1)))");
+    assert_eq!(fact.max_stack, 4);
+
+    // xs, the local y, then the three list items.
+    assert_eq!(
+        lambda("let f = xs -> { let y = 1; [y, y, y] }").max_stack,
+        5
+    );
+
+    // The program itself: three items, then the list and print.
+    assert_eq!(compile("print [1, 2, 3]").max_stack, 3);
+}
+
+// A recursive return from inside an if/else compiles to a tail call, while a
+// call whose value is still used does not.
+#[test]
+fn tail_calls() {
+    let sum = compile(
+        "let s = n a -> if (n == 0) then (return a) else {let m = n - 1; return (s m (a + n))}",
+    )
+    .chunk
+    .disassembly();
+    assert!(sum.contains("tail_call 0x02"), "{}", sum);
+    assert!(!sum.contains(": call "), "{}", sum);
+
+    let fact = compile("let f = n -> if (n == 0) then (return 1) else (return (n * (f (n - 1))))")
+        .chunk
+        .disassembly();
+    assert!(fact.contains(": call 0x01"), "{}", fact);
+    assert!(!fact.contains("tail_call"), "{}", fact);
+}
+
+// Compiles a program in two pieces, the way the REPL does line by line, and
+// runs each piece on the same VM so the second sees the first's globals.
+#[test]
+fn incremental() {
+    let ffi = common::ffi();
+    let ctx = ParserContext::default_bonsai();
+    let mut cc = Compiler::new(false);
+
+    let first = bonsai::parse("let scale = 3; let f = n -> n * scale", &ctx).unwrap();
+    let (f, start) = cc.compile_more(&first).unwrap();
+    assert_eq!(start, 0);
+    let mut vm = VM::new(Closure::new(f.clone()), &ffi);
+    assert!(matches!(
+        vm.run_at(Closure::new(f), start, false),
+        VMResult::Ok(_)
+    ));
+
+    // The second piece lands after the first in the same chunk, reusing its
+    // constants, and only the new code runs.
+    let second = bonsai::parse("scale = scale + 1; f 10", &ctx).unwrap();
+    let (f, start) = cc.compile_more(&second).unwrap();
+    assert!(start > 0);
+    let result = vm.run_at(Closure::new(f.clone()), start, false);
+    assert!(matches!(result, VMResult::Ok(Value::Int(40))));
+
+    // A piece that fails to compile leaves the program as it was.
+    let broken = bonsai::parse("break", &ctx).unwrap();
+    assert!(cc.compile_more(&broken).is_err());
+    let (again, _) = cc
+        .compile_more(&bonsai::parse("f 1", &ctx).unwrap())
+        .unwrap();
+    assert!(again.chunk.code.starts_with(&f.chunk.code));
+}
+
+// Functions go to their own table and the constants keep only scalars, with
+// each load still finding its value after the split.
+#[test]
+fn constants_and_functions_apart() {
+    let src = "let big = 1000000; let half = 0.5; let greet = s -> join [\"hi\", s] \" \"; \
+               let twice = x -> x * 2; [big, half, greet \"bo\", twice 21]";
+    let f = compile(src);
+    assert_eq!(f.chunk.functions.len(), 2);
+    assert!(f
+        .chunk
+        .constants
+        .iter()
+        .all(|x| !matches!(x, Value::Closure(_))));
+    assert_eq!(
+        common::run(src).debug_repr(),
+        "[1000000, 0.5, \"hi bo\", 42]"
+    );
+
+    // A lambda's own constants live in its chunk.
+    let greet = &f.chunk.functions[0].chunk;
+    assert!(greet.constants.iter().any(|x| x.to_string() == "hi"));
+}
@@ -0,0 +1,246 @@
+use bonsai::lexer::{lex, lex_spanned, Span, Tk};
+use bonsai::parser::ParserContext;
+
+mod common;
+
+fn first(src: &str) -> Tk {
+    lex(src.to_string()).remove(0)
+}
+
+// Comments leave the program unchanged, down to the bytecode.
+#[test]
+fn comments() {
+    let code = |src: &str| common::compile(src).chunk.code;
+    assert_eq!(code("let x = 5 // this is ignored"), code("let x = 5"));
+    assert_eq!(
+        code("let x = 5 // first\nlet y = x / 2"),
+        code("let x = 5\nlet y = x / 2")
+    );
+
+    // A single slash is still division.
+    assert_eq!(lex("x / 2".to_string())[1], Tk::NameInfix("/".to_string()));
+
+    // A comment at the end of the file needs no newline, and the newline
+    // ending one is kept.
+    assert_eq!(lex("//".to_string()), [Tk::Eof]);
+    assert_eq!(
+        lex("1 // one\n".to_string()),
+        [Tk::LitInt(1), Tk::NewLine, Tk::Eof]
+    );
+
+    // Block comments nest, and one left open is an error.
+    assert_eq!(
+        lex("1 /* a /* b */ c */ + 2".to_string()),
+        lex("1 + 2".to_string())
+    );
+    assert_eq!(code("let x = /* five */ 5"), code("let x = 5"));
+    assert_eq!(
+        lex("1 /* a /* b */".to_string()),
+        [
+            Tk::LitInt(1),
+            Tk::Error("Unterminated block comment".to_string()),
+            Tk::Eof
+        ]
+    );
+    // Neither half of a comment is taken into an operator run.
+    assert_eq!(lex("2 */* x */ 3".to_string()), lex("2 * 3".to_string()));
+}
+
+// A backslash at the end of a line carries the statement onto the next one.
+#[test]
+fn backslash_continues_a_line() {
+    assert_eq!(lex("1 + \\\n2".to_string()), lex("1 + 2".to_string()));
+    // Trailing whitespace after the backslash is allowed.
+    assert_eq!(lex("1 + \\  \r\n2".to_string()), lex("1 + 2".to_string()));
+
+    // Right after an operator, and where no operator asks for more.
+    let tokens = lex("a +\\\n b".to_string());
+    assert!(!tokens.contains(&Tk::NewLine), "{:?}", tokens);
+    assert_eq!(tokens.len(), 4);
+    assert_eq!(lex("f\\\n  x".to_string()), lex("f x".to_string()));
+
+    let tokens = lex("1 \\ 2".to_string());
+    assert!(matches!(tokens[1], Tk::Error(_)));
+}
+
+// Escapes are decoded by the lexer, so the string literal holds the characters.
+#[test]
+fn escapes() {
+    assert_eq!(first(r#""a\nb""#), Tk::LitStr("a\nb".to_string()));
+    assert_eq!(
+        first(r#""\t\r\0\\\"""#),
+        Tk::LitStr("\t\r\0\\\"".to_string())
+    );
+    assert_eq!(
+        first(r#""bad \q""#),
+        Tk::Error("Unknown escape sequence \\q".to_string())
+    );
+    // An escaped quote doesn't end the string.
+    assert_eq!(
+        first(r#""open \""#),
+        Tk::Error("Unterminated String".to_string())
+    );
+}
+
+// Integer literals in bases 16, 8 and 2, next to plain decimal ones.
+#[test]
+fn radix_literals() {
+    assert_eq!(first("0x1F"), Tk::LitInt(31));
+    assert_eq!(first("0o17"), Tk::LitInt(15));
+    assert_eq!(first("0b1010"), Tk::LitInt(10));
+    assert!(matches!(first("0x"), Tk::Error(_)));
+    assert!(matches!(first("0b12"), Tk::Error(_)));
+
+    assert_eq!(lex("0".to_string()), [Tk::LitInt(0), Tk::Eof]);
+    assert_eq!(first("0.5"), Tk::LitFloat(0.5));
+    assert_eq!(first("10"), Tk::LitInt(10));
+}
+
+// A number literal that doesn't fit is an error token, not a panic.
+#[test]
+fn number_overflow() {
+    assert_eq!(
+        lex("99999999999999999999".to_string()),
+        vec![
+            Tk::Error("invalid number literal 99999999999999999999".to_string()),
+            Tk::Eof
+        ]
+    );
+    assert_eq!(
+        lex("1.2.3".to_string()),
+        vec![
+            Tk::Error("invalid number literal 1.2.3".to_string()),
+            Tk::Eof
+        ]
+    );
+    // The largest int still lexes.
+    assert_eq!(
+        lex(isize::MAX.to_string()),
+        vec![Tk::LitInt(isize::MAX), Tk::Eof]
+    );
+
+    let err =
+        bonsai::parse("1 + 99999999999999999999", &ParserContext::default_bonsai()).unwrap_err();
+    assert!(err.0.starts_with("invalid number literal"), "{:?}", err);
+}
+
+// Character literals hold exactly one character, escapes included.
+#[test]
+fn char_literals() {
+    assert_eq!(first("'a'"), Tk::LitChar('a'));
+    assert_eq!(first("'λ'"), Tk::LitChar('λ'));
+    assert_eq!(first("'\\n'"), Tk::LitChar('\n'));
+    assert_eq!(first("'\\''"), Tk::LitChar('\''));
+    assert_eq!(first("'\"'"), Tk::LitChar('"'));
+
+    let one = Tk::Error("Character literal must hold one character".to_string());
+    assert_eq!(first("'ab'"), one);
+    assert_eq!(first("''"), one);
+    assert_eq!(
+        first("'a"),
+        Tk::Error("Unterminated character literal".to_string())
+    );
+    assert_eq!(
+        first("'\\q'"),
+        Tk::Error("Unknown escape sequence \\q".to_string())
+    );
+
+    // Lexing carries on after a bad literal.
+    assert_eq!(lex("'ab' 1".to_string())[1], Tk::LitInt(1));
+}
+
+// Names may use letters of any script, operators stay ASCII.
+#[test]
+fn unicode_names() {
+    assert_eq!(
+        lex("let λ = café_2 + 変数".to_string()),
+        [
+            Tk::Name("let".to_string()),
+            Tk::Name("λ".to_string()),
+            Tk::NameInfix("=".to_string()),
+            Tk::Name("café_2".to_string()),
+            Tk::NameInfix("+".to_string()),
+            Tk::Name("変数".to_string()),
+            Tk::Eof,
+        ]
+    );
+    assert_eq!(
+        lex("x `ünd` y".to_string())[1],
+        Tk::NameInfix("`ünd`".to_string())
+    );
+}
+
+// A stray character becomes an error token and the parser reports it,
+// instead of the lexer bringing the whole session down.
+#[test]
+fn unexpected_characters() {
+    let tokens = lex("1 ~ 2".to_string());
+    assert_eq!(tokens[1], Tk::Error("Unexpected character '~'".to_string()));
+    assert_eq!(tokens.last(), Some(&Tk::Eof));
+    assert_eq!(
+        lex("#".to_string()),
+        vec![Tk::Error("Unexpected character '#'".to_string()), Tk::Eof]
+    );
+
+    let ctx = ParserContext::default_bonsai();
+    let err = bonsai::parse("let x = 1\nx ~ 2", &ctx).unwrap_err();
+    assert_eq!(err.0, "Unexpected character '~' at line 2, col 3");
+    // The next line parses as usual.
+    assert!(bonsai::parse("x + 2", &ctx).is_ok());
+}
+
+// Tokens know where they start, and parse errors say where they happened.
+#[test]
+fn spans() {
+    let tokens = lex_spanned("let x = 1\n  print \"é\" x".to_string());
+    let spans: Vec<(usize, usize)> = tokens.iter().map(|(_, s)| (s.line, s.col)).collect();
+    assert_eq!(
+        spans,
+        [
+            (1, 1),
+            (1, 5),
+            (1, 7),
+            (1, 9),
+            (1, 10),
+            (2, 3),
+            (2, 9),
+            (2, 13),
+            (2, 14)
+        ]
+    );
+    assert_eq!(tokens[4].0, Tk::NewLine);
+    assert_eq!(tokens[8], (Tk::Eof, Span { line: 2, col: 14 }));
+
+    let ctx = ParserContext::default_bonsai();
+    let e = bonsai::parse("let x = 1\nprint (x ]", &ctx).unwrap_err();
+    assert_eq!(e.0, "Unexpected token RSquare at line 2, col 10");
+    let e = bonsai::parse("print 1\nprint \"open", &ctx).unwrap_err();
+    assert_eq!(e.0, "Unterminated String at line 2, col 7");
+    let e = bonsai::parse("let x = 1\n  + 2", &ctx).unwrap_err();
+    assert!(e.0.ends_with("at line 2, col 3"), "{}", e.0);
+}
+
+// => and |> are reserved operators, wherever they start a run of special
+// characters, while other runs stay infix names.
+#[test]
+fn reserved_operators() {
+    let op = |s: &str| Tk::Operator(s.to_string());
+    let infix = |s: &str| Tk::NameInfix(s.to_string());
+    assert_eq!(
+        lex("x => y |> f".to_string()),
+        [
+            Tk::Name("x".to_string()),
+            op("=>"),
+            Tk::Name("y".to_string()),
+            op("|>"),
+            Tk::Name("f".to_string()),
+            Tk::Eof
+        ]
+    );
+    assert_eq!(lex("|>=>".to_string()), [op("|>"), op("=>"), Tk::Eof]);
+    assert_eq!(lex("=>=".to_string()), [op("=>"), infix("="), Tk::Eof]);
+    // Only at the front of a run, so these are names like any other.
+    assert_eq!(lex(">=".to_string()), [infix(">="), Tk::Eof]);
+    assert_eq!(lex("==>".to_string()), [infix("==>"), Tk::Eof]);
+    assert_eq!(lex("||>".to_string()), [infix("||>"), Tk::Eof]);
+}
@@ -0,0 +1,111 @@
+use std::thread;
+
+use bonsai::common::Core;
+use bonsai::compiler::Compiler;
+use bonsai::parser::ParserContext;
+use bonsai::value::Value;
+
+mod common;
+
+// if takes blocks without then, and malformed ifs are parse errors.
+#[test]
+fn if_blocks() {
+    let ctx = ParserContext::default_bonsai();
+    for src in [
+        "if x { 1 }",
+        "if x { 1 } else { 2 }",
+        "if x then 1 else 2",
+        "if x { 1 } else if y { 2 } else { 3 }",
+    ] {
+        assert!(bonsai::parse(src, &ctx).is_ok(), "{}", src);
+    }
+    for src in [
+        "if x",
+        "if x 1",
+        "if x { 1 } else",
+        "if then 1",
+        "if x else { 2 }",
+        "if { a }",
+    ] {
+        let err = bonsai::parse(src, &ctx).unwrap_err();
+        assert_eq!(
+            err.0, "Expected if cond then body else body or if cond { body } else { body }",
+            "{}",
+            src
+        );
+    }
+}
+
+// Deep nesting is an error rather than a stack overflow, whether it comes from
+// parentheses or from a long flat chain of operators. The default limit fits
+// the stack of a main thread, test threads get less so this one runs in its own.
+#[test]
+fn nesting_depth_limit() {
+    thread::Builder::new()
+        .stack_size(8 << 20)
+        .spawn(check_nesting_depth_limit)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+fn check_nesting_depth_limit() {
+    let ctx = ParserContext::default_bonsai();
+    let limit = format!("Exceeded maximum nesting depth of {}", ctx.max_depth());
+
+    let parens = format!("{}1{}", "(".repeat(10000), ")".repeat(10000));
+    assert_eq!(bonsai::parse(&parens, &ctx).unwrap_err().0, limit);
+    let chain = format!("let x = 1{}", " + 1".repeat(100000));
+    assert_eq!(bonsai::parse(&chain, &ctx).unwrap_err().0, limit);
+
+    // The limit holds for the next parse, where shorter chains still work.
+    let short = format!("1{}", " + 1".repeat(100));
+    assert!(matches!(common::run(&short), Value::Int(101)));
+
+    // The compiler checks Core built without the parser.
+    let mut deep = Core::Lit(Value::Int(1));
+    for _ in 0..10000 {
+        deep = Core::Block(vec![deep]);
+    }
+    let err = Compiler::new(false).compile_program(&deep).unwrap_err();
+    assert_eq!(err.0, limit);
+}
+
+// The two parsing phases turn a let into Core, wrapped in the program's block.
+#[test]
+fn let_to_core() {
+    let core = bonsai::parse("let x = 1", &ParserContext::default_bonsai()).unwrap();
+    let Core::Block(exprs) = core else {
+        panic!("expected a block, got {:?}", core);
+    };
+    match &exprs[..] {
+        [Core::Let(name, value)] => {
+            assert_eq!(name, "x");
+            assert!(matches!(**value, Core::Lit(Value::Int(1))), "{:?}", value);
+        }
+        _ => panic!("expected one let, got {:?}", exprs),
+    }
+}
+
+// Malformed input is a ParseError from parse, never a panic.
+#[test]
+fn malformed_input_is_an_error() {
+    let ctx = ParserContext::default_bonsai();
+    for (src, message) in [
+        ("= 5", "Expected let name = value or name = value"),
+        ("a b = 5", "Expected let name = value or name = value"),
+        ("let a b = 5", "Expected let name = value or name = value"),
+        ("1 = 5", "Expected let name = value or name = value"),
+        ("1 -> 2", "Expected parameter names before ->"),
+        ("(1, 2)", "Tuples aren't values, use a list [a, b] instead"),
+        (
+            "print (1, 2)",
+            "Tuples aren't values, use a list [a, b] instead",
+        ),
+    ] {
+        match bonsai::parse(src, &ctx) {
+            Err(e) => assert_eq!(e.0, message, "{}", src),
+            Ok(core) => panic!("{} parsed to {:?}", src, core),
+        }
+    }
+}
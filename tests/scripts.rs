@@ -0,0 +1,78 @@
+// Runs each tests/*.bns script and compares what it prints with the .out
+// file beside it. Scripts under tests/fail/ must exit with an error, their
+// stderr is compared with a .err file as well.
+// BONSAI_BLESS=1 cargo test rewrites the expected files from the output.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+mod common;
+
+fn scripts(dir: &Path) -> Vec<PathBuf> {
+    let mut found: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bns"))
+        .collect();
+    found.sort();
+    found
+}
+
+// Compares `actual` with the file at `path`, or writes it there when blessing.
+fn expect(path: &Path, actual: &str, failures: &mut Vec<String>) {
+    if env::var_os("BONSAI_BLESS").is_some() {
+        fs::write(path, actual).unwrap();
+        return;
+    }
+    match fs::read_to_string(path) {
+        Ok(expected) if expected == actual => (),
+        Ok(expected) => failures.push(format!(
+            "{} differs\n--- expected\n{}--- actual\n{}",
+            path.display(),
+            expected,
+            actual
+        )),
+        Err(_) => failures.push(format!("{} is missing", path.display())),
+    }
+}
+
+fn run_all(dir: &str, should_fail: bool) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut failures = vec![];
+    for script in scripts(&root.join(dir)) {
+        let relative = script.strip_prefix(root).unwrap();
+        let output = common::bonsai()
+            .arg(relative)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+
+        if output.status.success() == should_fail {
+            failures.push(format!(
+                "{} exited with {}\n{}",
+                relative.display(),
+                output.status,
+                stderr
+            ));
+            continue;
+        }
+        expect(&script.with_extension("out"), &stdout, &mut failures);
+        if should_fail {
+            expect(&script.with_extension("err"), &stderr, &mut failures);
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn scripts_print_expected_output() {
+    run_all("tests", false);
+}
+
+#[test]
+fn failing_scripts_report_expected_errors() {
+    run_all("tests/fail", true);
+}
@@ -0,0 +1,41 @@
+use bonsai::value::Value;
+use bonsai::vm::ErrorKind;
+
+mod common;
+
+// debug_repr shows what kind of value it is, Display is what print shows.
+#[test]
+fn debug_repr_quotes_strings() {
+    let s = Value::Str("abc".to_string());
+    assert_eq!(s.debug_repr(), "\"abc\"");
+    assert_eq!(s.to_string(), "abc");
+
+    let xs = common::run("[\"a\", [1, \"b\"], none]");
+    assert_eq!(xs.debug_repr(), "[\"a\", [1, \"b\"], None]");
+    assert_eq!(xs.to_string(), "[a, [1, b], None]");
+
+    // Escapes are shown in the repr and written out by Display.
+    let s = Value::Str("a\n\"b\"".to_string());
+    assert_eq!(s.debug_repr(), "\"a\\n\\\"b\\\"\"");
+    assert_eq!(s.to_string(), "a\n\"b\"");
+
+    let f = common::run("x -> x");
+    assert_eq!(f.debug_repr(), "<closure/1>");
+    assert_eq!(
+        Value::Native("len".to_string()).debug_repr(),
+        "<native len>"
+    );
+}
+
+// The expect_ helpers fail with a TypeError naming both types.
+#[test]
+fn expect_int_names_the_type() {
+    assert_eq!(Value::Int(3).expect_int().unwrap(), 3);
+    let e = Value::Str("3".to_string()).expect_int().unwrap_err();
+    assert_eq!(e.kind, ErrorKind::TypeError);
+    assert_eq!(e.message, "expected int, got str");
+
+    // A native using it reports the same.
+    let e = common::try_run("repeat_str \"a\" \"3\"").unwrap_err();
+    assert_eq!(e.message, "expected int, got str");
+}
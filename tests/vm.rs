@@ -0,0 +1,366 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::rc::Rc;
+
+use bonsai::common::{Chunk, Op};
+use bonsai::compiler::Compiler;
+use bonsai::native::{expect_args, Arity};
+use bonsai::parser::ParserContext;
+use bonsai::value::{Closure, Function, Value};
+use bonsai::vm::{ErrorKind, RuntimeError, VMResult, VmState, VM};
+
+mod common;
+
+use common::compile;
+
+// Globals given to a VM up front are there for the program it runs.
+#[test]
+fn globals_from_the_host() {
+    let ffi = common::ffi();
+    let globals = HashMap::from([
+        ("limit".to_string(), Value::Int(9)),
+        ("scale".to_string(), Value::Int(3)),
+    ]);
+    let f = compile("limit = limit + 1\nlimit * scale");
+    let mut vm = VM::with_globals(Closure::new(f), &ffi, globals);
+    assert!(matches!(vm.run(false), VMResult::Ok(Value::Int(30))));
+    assert_eq!(vm.global_names(), ["limit", "scale"]);
+}
+
+// Read-only globals from the host can be read by a script but not changed.
+#[test]
+fn read_only_globals() {
+    let ffi = common::ffi();
+    let run = |src: &str| {
+        let mut vm = VM::new(Closure::new(compile(src)), &ffi);
+        vm.define_read_only("port", Value::Int(8080));
+        vm.run(false)
+    };
+
+    assert!(matches!(run("port + 1"), VMResult::Ok(Value::Int(8081))));
+    for src in [
+        "port = 80",
+        "let port = 80",
+        "let f = x -> (port = x)\nf 80",
+    ] {
+        match run(src) {
+            VMResult::Error(e) => assert_eq!(e.message, "can't change read-only global port"),
+            VMResult::Ok(x) => panic!("{} gave {}", src, x),
+        }
+    }
+    // The error can be caught like any other.
+    let caught = run("try { port = 80 } catch e { port }");
+    assert!(matches!(caught, VMResult::Ok(Value::Int(8080))));
+}
+
+// Each global access finds its slot by name only once, then through the
+// chunk's cache.
+#[test]
+fn global_cache() {
+    let src = "
+let total = 0
+let i = 0
+let n = 100000
+let step = 1
+loop {
+    if (i == n) then break
+    total = total + step
+    if (i == 500) then (step = 2)
+    i = i + 1
+}
+total
+";
+    let ffi = common::ffi();
+    let f = compile(src);
+    let mut vm = VM::new(Closure::new(f.clone()), &ffi);
+    let (result, stats) = vm.run_with_stats(false);
+    // Reassigned globals are seen through the cache.
+    assert!(matches!(result, VMResult::Ok(Value::Int(199499))));
+    // One lookup per instruction reading or writing a global, not per run.
+    assert!(stats.global_lookups <= 20, "{:?}", stats);
+
+    // A second VM running the same chunk has slots of its own.
+    let mut other = VM::with_globals(
+        Closure::new(f),
+        &ffi,
+        [("unused".to_string(), Value::None)].into(),
+    );
+    assert!(matches!(other.run(false), VMResult::Ok(Value::Int(199499))));
+}
+
+// Natives called with the wrong number of arguments all fail the same way.
+#[test]
+fn native_arity() {
+    let e = common::try_run("nth [1, 2]").unwrap_err();
+    assert_eq!(e.kind, ErrorKind::TypeError);
+    assert_eq!(e.message, "nth expected 2 arguments, got 1");
+    let e = common::try_run("len [1] [2]").unwrap_err();
+    assert!(e.message.contains("got 2"), "{}", e.message);
+
+    let args = [Value::Int(1)];
+    assert!(expect_args("f", Arity::AtLeast(1), &args).is_ok());
+    let e = expect_args("f", Arity::Range(2, 3), &args).unwrap_err();
+    assert_eq!(e.message, "f expected 2 to 3 arguments, got 1");
+}
+
+// Strict numbers turn mixing ints and floats in arithmetic into a TypeError,
+// while arithmetic on two floats, or after an explicit conversion, still works.
+#[test]
+fn strict_numbers() {
+    let ffi = common::ffi();
+    let run = |src: &str, strict: bool| -> Result<Value, RuntimeError> {
+        let mut vm = VM::new(Closure::new(compile(src)), &ffi);
+        vm.set_strict_numbers(strict);
+        match vm.run(false) {
+            VMResult::Ok(x) => Ok(x),
+            VMResult::Error(e) => Err(e),
+        }
+    };
+
+    for src in [
+        "1 + 2.0",
+        "2.5 * 2",
+        "1 - 0.5",
+        "3 / 1.5",
+        "let add = +; add 1 2.0",
+    ] {
+        assert!(run(src, false).is_ok(), "{} should promote", src);
+        let e = run(src, true).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::TypeError, "{}", src);
+    }
+
+    assert!(matches!(run("1.0 + 2.0", true), Ok(Value::Float(3.0))));
+    assert!(matches!(
+        run("(float 1) + 2.0", true),
+        Ok(Value::Float(3.0))
+    ));
+    assert!(matches!(run("1 + (int 2.9)", true), Ok(Value::Int(3))));
+    // Comparisons and int division aren't arithmetic mixing.
+    assert!(matches!(run("1 < 2.0", true), Ok(Value::Bool(true))));
+    assert!(matches!(run("1 / 2", true), Ok(Value::Float(0.5))));
+}
+
+// Peak memory of a program that builds a large list and drops it again, and
+// a memory limit stopping the same program.
+#[test]
+fn memory() {
+    // The big list is only alive while build runs, pushes grow it one by one.
+    let src = "
+let build = n -> {
+    let xs = []
+    let i = 0
+    loop {
+        if (i == n) then break
+        push xs i
+        i = i + 1
+    }
+    len xs
+}
+build 10000
+";
+    let ffi = common::ffi();
+    let vm = || VM::new(Closure::new(compile(src)), &ffi);
+    let list_bytes = 10000 * mem::size_of::<Value>();
+
+    let mut vm1 = vm();
+    let (result, stats) = vm1.run_with_stats(false);
+    assert!(matches!(result, VMResult::Ok(Value::Int(10000))));
+    assert!(stats.peak_memory >= list_bytes);
+    assert!(stats.peak_memory < 2 * list_bytes);
+    // The list was dropped along with build's frame.
+    assert!(vm1.memory_in_use() < list_bytes / 100);
+
+    let mut vm2 = vm();
+    vm2.set_memory_limit(Some(list_bytes / 2));
+    match vm2.run(false) {
+        VMResult::Error(e) => assert!(e.message.contains("memory limit"), "{}", e),
+        VMResult::Ok(_) => panic!("the limit should have stopped the run"),
+    }
+
+    let mut vm3 = vm();
+    vm3.set_memory_limit(Some(2 * list_bytes));
+    assert!(matches!(vm3.run(false), VMResult::Ok(Value::Int(10000))));
+}
+
+// A script registers closures as event handlers through a native, the host
+// keeps them and fires events at them after the script has finished running.
+#[test]
+fn callbacks() {
+    let src = "
+let clicks = 0
+on_event (button -> { clicks = clicks + 1; clicks })
+on_event (button -> if (button == 0) then (panic \"bad event\") else button)
+";
+    let handlers: Rc<RefCell<Vec<Value>>> = Rc::default();
+    let mut ffi = common::ffi();
+    let registered = Rc::clone(&handlers);
+    ffi.insert(
+        "on_event".to_string(),
+        Arity::Exact(1),
+        Box::new(move |_, args| {
+            registered.borrow_mut().push(args[0].clone());
+            Ok(Value::None)
+        }),
+    );
+
+    let mut vm = VM::new(Closure::new(compile(src)), &ffi);
+    assert!(matches!(vm.run(false), VMResult::Ok(Value::None)));
+
+    // Handlers may register more handlers, so fire at a copy of the list.
+    let fire = |vm: &mut VM, button: isize| -> Vec<Result<Value, RuntimeError>> {
+        let current = handlers.borrow().clone();
+        current
+            .iter()
+            .map(|h| vm.call_value(h, &[Value::Int(button)]))
+            .collect()
+    };
+
+    let results = fire(&mut vm, 1);
+    assert!(matches!(results[0], Ok(Value::Int(1))));
+    assert!(matches!(results[1], Ok(Value::Int(1))));
+
+    // A failing handler doesn't stop the VM from taking later events.
+    let results = fire(&mut vm, 0);
+    assert!(matches!(results[0], Ok(Value::Int(2))));
+    assert!(matches!(&results[1], Err(e) if e.message == "bad event"));
+
+    let results = fire(&mut vm, 2);
+    assert!(matches!(results[0], Ok(Value::Int(3))));
+    assert!(matches!(results[1], Ok(Value::Int(2))));
+}
+
+// A script checkpoints itself halfway through a loop. Resuming from the
+// checkpoint must end at the same result, so nothing done after it leaked in.
+#[test]
+fn checkpoint() {
+    let src = "
+let seen = []
+let total = 0
+for i in 1 to 11 {
+    push seen i
+    total = total + i
+    if (i == 5) then (checkpoint ())
+}
+[total, len seen]
+";
+    let saved: Rc<RefCell<Option<VmState>>> = Rc::default();
+    let mut ffi = common::ffi();
+    let slot = Rc::clone(&saved);
+    ffi.insert(
+        "checkpoint".to_string(),
+        Arity::Exact(0),
+        Box::new(move |vm, _| {
+            *slot.borrow_mut() = Some(vm.snapshot());
+            Ok(Value::None)
+        }),
+    );
+
+    let mut vm = VM::new(Closure::new(compile(src)), &ffi);
+    let VMResult::Ok(finished) = vm.run(false) else {
+        panic!("script failed");
+    };
+    assert_eq!(finished.debug_repr(), "[55, 10]");
+
+    // Resuming twice from the same checkpoint gives the same result each time.
+    let state = saved.borrow_mut().take().unwrap();
+    for _ in 0..2 {
+        vm.restore(&state);
+        let VMResult::Ok(resumed) = vm.resume(Value::None, false) else {
+            panic!("resumed script failed");
+        };
+        assert_eq!(resumed.debug_repr(), finished.debug_repr());
+    }
+}
+
+// A native hands out 3, 2, 1 and then None, a while let loop in the script
+// consumes it and its body runs once per value.
+#[test]
+fn while_let_over_a_native() {
+    let src = "
+let runs = 0
+let sum = 0
+while let x = (next_value ()) {
+    runs = runs + 1
+    sum = sum + x
+}
+[runs, sum]
+";
+    let left = Rc::new(Cell::new(3));
+    let mut ffi = common::ffi();
+    let counter = Rc::clone(&left);
+    ffi.insert(
+        "next_value".to_string(),
+        Arity::Exact(0),
+        Box::new(move |_, _| match counter.get() {
+            0 => Ok(Value::None),
+            n => {
+                counter.set(n - 1);
+                Ok(Value::Int(n))
+            }
+        }),
+    );
+
+    assert_eq!(common::run_with(src, &ffi).to_string(), "[3, 6]");
+    assert_eq!(left.get(), 0);
+}
+
+// Two files compiled as one program, the second using what the first defines.
+#[test]
+fn joined_sources() {
+    let contents: Vec<String> = ["tests/join/shapes.bns", "tests/join/main.bns"]
+        .iter()
+        .map(|f| fs::read_to_string(f).unwrap())
+        .collect();
+    let sources: Vec<&str> = contents.iter().map(|c| c.as_str()).collect();
+    let ffi = common::ffi();
+
+    let core = bonsai::parse_all(&sources, &ParserContext::default_bonsai()).unwrap();
+    let f = Compiler::new(false).compile_program(&core).unwrap();
+    let mut vm = VM::new(Closure::new(f), &ffi);
+    assert!(matches!(vm.run(false), VMResult::Ok(Value::Int(11))));
+
+    // Alone, the second file doesn't know square.
+    assert!(common::try_run_with(&contents[1], &ffi).is_err());
+}
+
+// A straight line program runs each of its instructions once, and every call
+// still running adds a frame on top of the program's own.
+#[test]
+fn run_stats() {
+    let ffi = common::ffi();
+    let stats = |f| VM::new(Closure::new(f), &ffi).run_with_stats(false).1;
+
+    let f = compile("let x = 1 + 2; [x, x * 3]");
+    let ran = stats(f.clone());
+    assert_eq!(ran.instructions, f.chunk.instructions().len());
+    assert_eq!(ran.max_frames, 1);
+
+    let depth = |n| {
+        let src = format!(
+            "let f = n -> if (n == 0) then 0 else (1 + (f (n - 1)))\nf {}",
+            n
+        );
+        stats(compile(&src)).max_frames
+    };
+    assert_eq!(depth(0), 2);
+    assert_eq!(depth(10), 12);
+    // Tail calls reuse their frame.
+    let tail = compile("let f = n -> if (n == 0) then (return 0) else (return (f (n - 1)))\nf 10");
+    assert_eq!(stats(tail).max_frames, 2);
+}
+
+// A function built by the host has no max_stack worked out, calling it with
+// arguments still works.
+#[test]
+fn hand_built_function() {
+    let ffi = common::ffi();
+    let code = vec![Op::GetLocal as u8, 0, Op::Return as u8];
+    let f = Function::new(1, 0, Chunk::new(code, vec![]));
+    assert_eq!(f.max_stack, 0);
+
+    let mut vm = VM::new(Closure::new(compile("none")), &ffi);
+    let result = vm.call_value(&Value::Closure(Closure::new(f)), &[Value::Int(7)]);
+    assert!(matches!(result, Ok(Value::Int(7))), "{:?}", result);
+}